@@ -0,0 +1,1025 @@
+//! The command line of mapwatcher: all options with their descriptions
+//! for `--help`, and the parsing of them into `Args`.
+
+use mapwatcher::{
+    parse_size, Backing, DiffEvent, Format, GroupBy, Layout, LogLevel, Metric, Options, Source,
+    TimeFormat,
+};
+use std::io::IsTerminal;
+use std::time::Duration;
+
+pub const VERSION: &str = "0.3";
+
+/// The signals `--on-signal` can wait for, with their numbers on Linux.
+pub const SIGUSR1: i32 = 10;
+pub const SIGUSR2: i32 = 12;
+
+/// The exit statuses, so that scripts can tell why mapwatcher stopped.
+/// Success is 0, after `--max-iterations`, Ctrl-C or a one-off output.
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_PROCESS_EXITED: i32 = 2;
+pub const EXIT_PERMISSION_DENIED: i32 = 3;
+pub const EXIT_PARSE_ERROR: i32 = 4;
+pub const EXIT_ALERT: i32 = 5;
+
+/// The exit statuses with a short description, for `--help`.
+const EXIT_STATUSES: &[(i32, &str)] = &[
+    (
+        0,
+        "done, after --max-iterations N, Ctrl-C or a one-off output",
+    ),
+    (EXIT_ERROR, "bad command line or another error"),
+    (EXIT_PROCESS_EXITED, "all watched processes have exited"),
+    (
+        EXIT_PERMISSION_DENIED,
+        "no permission to read the maps of a process",
+    ),
+    (
+        EXIT_PARSE_ERROR,
+        "a smaps file or recording could not be parsed",
+    ),
+    (EXIT_ALERT, "an alert was raised with --exit-on-alert"),
+];
+
+/// Everything given on the command line. `positional` holds the PIDS (or
+/// nothing with `--name` and `--cgroup`) and the DELAY, which depend on
+/// the mode.
+pub struct Args {
+    pub opts: Options,
+    pub log_level: LogLevel,
+    pub file: Option<String>,
+    pub prometheus: Option<String>,
+    pub record: Option<String>,
+    pub compress: bool,
+    pub replay_file: Option<String>,
+    pub diff_files: Option<(String, String)>,
+    pub baseline: Option<String>,
+    pub adaptive: bool,
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+    pub mark: bool,                        // also when only --diff-mark is given
+    pub diff_mark: Option<String>,         // the mark to diff against
+    pub convert: Option<(String, String)>, // the target format and the recording
+    pub name: Option<String>,
+    pub follow_name: bool,
+    pub cgroup: Option<String>,
+    pub rollup: bool,
+    pub all: bool,
+    pub exec: Option<Vec<String>>, // the command line after --exec ... --
+    pub leak_window: Option<usize>,
+    pub map_history: Option<usize>,
+    pub avg_window: Option<usize>,
+    pub alert_rss: Option<u64>,
+    pub alert_pss: Option<u64>,
+    pub alert_swap: Option<u64>,
+    pub alert_growth: Option<f64>, // in percent
+    pub alert_growth_metric: Metric,
+    pub alert_growth_since_start: bool,
+    pub wx_alert: bool,
+    pub exit_on_alert: bool,
+    pub max_iterations: Option<u64>,
+    pub until_rss: Option<u64>,
+    pub until_map_appears: Option<String>,
+    pub until_map_gone: Option<String>,
+    pub max_retries: u32,
+    pub once: bool,
+    pub header: bool,
+    pub legend: bool,
+    pub threads: bool,
+    pub clear_refs: bool,
+    pub summary_interval: Option<u64>,
+    pub heartbeat: Option<Duration>,
+    pub on_signal: Option<i32>,
+    pub ignore_file: Option<String>,
+    pub show_interval: bool,
+    pub output: Option<String>,
+    pub append: bool,
+    pub positional: Vec<String>,
+}
+
+/// All options with their values and a short description, for `--help`.
+const OPTIONS: &[(&str, &str)] = &[
+    (
+        "--format text|json|ndjson|csv",
+        "output format, default text",
+    ),
+    ("--max-iterations N, -n N", "stop after N diffs"),
+    (
+        "--max-retries K",
+        "skip up to K samples in a row which fail to read (default 3)",
+    ),
+    ("--once", "show the current maps and exit, without DELAY"),
+    (
+        "--metrics LIST",
+        "compared metrics, default size,rss,pss,swap",
+    ),
+    ("--human, -h", "show sizes in binary units like 512.0 MiB"),
+    (
+        "--events LIST",
+        "only show these diff events of mmap, drop, changed, moved",
+    ),
+    (
+        "--filter LIST",
+        "only show maps whose name contains one of these",
+    ),
+    (
+        "--exclude LIST",
+        "hide maps whose name contains one of these",
+    ),
+    (
+        "--clear-refs",
+        "reset the referenced bits after each sample, for the working set",
+    ),
+    (
+        "--include-unnamed",
+        "also report diff events of anonymous maps without a name",
+    ),
+    (
+        "--from-to",
+        "show every event as old -> new (delta) for each metric",
+    ),
+    (
+        "--hugepage-summary",
+        "add the huge page totals to the totals and diffs",
+    ),
+    (
+        "--ignore-file FILE",
+        "hide maps matching a substring or glob in FILE, reread on changes",
+    ),
+    (
+        "--ignore-totals",
+        "leave the maps of --ignore-file out of the totals, too",
+    ),
+    (
+        "--group-by name|type",
+        "show and diff summed up groups of maps",
+    ),
+    (
+        "--sort-by METRIC",
+        "show maps and changes largest first, or by address",
+    ),
+    ("--top N", "only show the N largest maps or changes"),
+    (
+        "--max-events N",
+        "show at most N events per diff, the largest, and how many more",
+    ),
+    (
+        "--watch-top N",
+        "like top, show the N maps with the largest pss every time",
+    ),
+    ("--totals", "print the totals after each diff"),
+    (
+        "--header",
+        "print the command line and start time before each diff",
+    ),
+    (
+        "--threads",
+        "show the threads with their stacks, and when they start or end",
+    ),
+    (
+        "--show-interval",
+        "print the measured time between samples with each diff",
+    ),
+    ("--quiet, --no-initial", "do not show the initial maps"),
+    (
+        "--compact",
+        "show maps as one row each, the default from 100 maps",
+    ),
+    ("--verbose", "show all fields of each map on several lines"),
+    ("--dirty-only", "only show maps whose dirty pages changed"),
+    (
+        "--delta-threshold SIZE",
+        "hide changes of a map below SIZE in each metric",
+    ),
+    (
+        "--anonymous-only",
+        "only show maps without a file, like the heap",
+    ),
+    ("--file-only", "only show maps backed by a file"),
+    (
+        "--color auto|always|never",
+        "color the diff lines, auto does on a terminal",
+    ),
+    (
+        "--time-format FORMAT",
+        "rfc3339, unix or a time crate format description",
+    ),
+    ("--name NAME", "watch the process with this command name"),
+    (
+        "--follow-name",
+        "wait for the process to be restarted when it exits",
+    ),
+    ("--rollup", "only watch the totals from smaps_rollup"),
+    (
+        "--all",
+        "watch the rollups of all processes, show the top rss growers",
+    ),
+    (
+        "--source smaps|rollup|maps",
+        "read smaps, smaps_rollup like --rollup or just maps",
+    ),
+    (
+        "--alert-rss SIZE",
+        "alert when the total rss is above SIZE, like 2G",
+    ),
+    ("--alert-pss SIZE", "alert when the total pss is above SIZE"),
+    (
+        "--swap-alert SIZE",
+        "alert when the total swap is above SIZE",
+    ),
+    (
+        "--alert-growth-pct PCT",
+        "alert when the total rss grows by more than PCT percent",
+    ),
+    (
+        "--alert-growth-metric METRIC",
+        "the total of --alert-growth-pct: rss, pss or swap",
+    ),
+    (
+        "--alert-growth-since REF",
+        "grow since the previous (default) or the first sample (start)",
+    ),
+    (
+        "--wx-alert",
+        "alert about maps which are writable and executable",
+    ),
+    (
+        "--until-rss SIZE",
+        "stop once the total rss of a process is above SIZE",
+    ),
+    (
+        "--until-map-appears NAME",
+        "stop once a map whose name contains NAME appears",
+    ),
+    (
+        "--until-map-gone NAME",
+        "stop once the last map with a name containing NAME is gone",
+    ),
+    (
+        "--exit-on-alert",
+        "exit with status 5 after the first alert",
+    ),
+    (
+        "--leak-window N",
+        "flag maps whose rss grew in each of the last N samples",
+    ),
+    (
+        "--summary-interval N",
+        "only show every Nth diff fully, a line with the totals otherwise",
+    ),
+    (
+        "--adaptive",
+        "halve the delay after changes and double it when quiet",
+    ),
+    (
+        "--min-interval DELAY",
+        "the shortest delay of --adaptive, default DELAY / 8",
+    ),
+    (
+        "--max-interval DELAY",
+        "the longest delay of --adaptive, default DELAY * 8",
+    ),
+    (
+        "--heartbeat DELAY",
+        "say every DELAY that a process without changes is still watched",
+    ),
+    (
+        "--on-signal SIGUSR1|SIGUSR2",
+        "take a sample whenever the signal arrives, without DELAY",
+    ),
+    (
+        "--avg-window N",
+        "show moving averages over N samples instead of diffs",
+    ),
+    (
+        "--map-history N",
+        "show the number of maps over the last N samples",
+    ),
+    (
+        "--prometheus FILE",
+        "keep FILE updated with metrics for node_exporter",
+    ),
+    (
+        "--output FILE",
+        "write the output to FILE instead of stdout",
+    ),
+    (
+        "--append",
+        "append to the --output FILE instead of truncating it",
+    ),
+    ("--record FILE", "append every snapshot to FILE"),
+    (
+        "--compress",
+        "gzip the --record FILE (needs gzip), also for names ending in .gz",
+    ),
+    (
+        "--replay FILE",
+        "show the snapshots recorded in FILE, a gzipped one needs gzip",
+    ),
+    (
+        "--convert prometheus|csv FILE",
+        "convert the snapshots recorded in FILE, without diffs",
+    ),
+    ("--file SMAPSFILE", "show a captured smaps file"),
+    (
+        "--diff OLD NEW",
+        "show the diff between two captured smaps files",
+    ),
+    (
+        "--baseline SMAPSFILE",
+        "diff every sample against a captured smaps file",
+    ),
+    (
+        "--mark",
+        "take a sample named by each line on stdin as a mark",
+    ),
+    (
+        "--diff-mark NAME",
+        "diff every sample against the mark NAME once it is taken",
+    ),
+    (
+        "--exec",
+        "run the command after --, watch it from its start and exit like it",
+    ),
+    (
+        "--cgroup CGROUP",
+        "watch the processes in CGROUP, its directory or cgroup.procs",
+    ),
+    (
+        "--no-legend",
+        "do not explain the tags of the diffs before watching",
+    ),
+    ("--help", "show this help"),
+    ("--version", "show the version"),
+    (
+        "-v, -vv",
+        "also log infos, or infos and debug messages, to stderr",
+    ),
+];
+
+/// Fetches the value following a command line option or exits with a
+/// message if there is none.
+fn option_value(raw_args: &mut impl Iterator<Item = String>, option: &str) -> String {
+    match raw_args.next() {
+        Some(value) => value,
+        None => {
+            eprintln!("{} needs a value", option);
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// The forms of the command line, without the program name.
+const USAGE: &[&str] = &[
+    "[OPTIONS] PIDS DELAY",
+    "[OPTIONS] --name NAME [--follow-name] DELAY",
+    "[OPTIONS] --cgroup CGROUP DELAY",
+    "[OPTIONS] --rollup PIDS DELAY",
+    "[OPTIONS] --all [--top N] DELAY",
+    "[OPTIONS] --exec DELAY -- COMMAND [ARGS...]",
+    "[OPTIONS] --on-signal SIGUSR1|SIGUSR2 PIDS",
+    "[OPTIONS] --once PIDS",
+    "[OPTIONS] --file SMAPSFILE",
+    "[OPTIONS] --replay RECORDING",
+    "[OPTIONS] --diff OLDSMAPS NEWSMAPS",
+];
+
+/// Prints the short usage to stderr and exits with an error.
+pub fn usage() -> ! {
+    for (i, form) in USAGE.iter().enumerate() {
+        let prefix = if i == 0 { "Usage:" } else { "      " };
+        eprintln!("{} mapwatcher {}", prefix, form);
+    }
+    eprintln!("See mapwatcher --help for all options.");
+    std::process::exit(EXIT_ERROR);
+}
+
+/// Prints the usage and all options to stdout and exits.
+fn help() -> ! {
+    println!(
+        "mapwatcher {} - watch the memory maps of processes\n",
+        VERSION
+    );
+    for (i, form) in USAGE.iter().enumerate() {
+        let prefix = if i == 0 { "Usage:" } else { "      " };
+        println!("{} mapwatcher {}", prefix, form);
+    }
+    println!();
+    println!("PIDS is a PID or a comma separated list of PIDs (self is mapwatcher itself),");
+    println!("DELAY the time between samples like 2, 500ms or 1m.\n");
+    println!("Options:");
+    let width = OPTIONS.iter().map(|(o, _)| o.len()).max().unwrap_or(0);
+    for (option, description) in OPTIONS {
+        println!("  {:width$}  {}", option, description, width = width);
+    }
+    println!("\nExit status:");
+    for (status, description) in EXIT_STATUSES {
+        println!("  {}  {}", status, description);
+    }
+    std::process::exit(0);
+}
+
+/// Parses a comma separated list of PIDs like `1234,1235,1236`, where
+/// `self` stands for mapwatcher itself.
+pub fn parse_pids(s: &str) -> Result<Vec<i32>, String> {
+    s.split(',')
+        .map(|pid| match pid {
+            "self" => Ok(std::process::id() as i32),
+            _ => pid.parse::<i32>().map_err(|_| format!("Bad PID: {}", pid)),
+        })
+        .collect()
+}
+
+/// Parses a delay like `2`, `0.5`, `500ms`, `2s`, `1m` or `1h`, a plain
+/// number is in seconds.
+pub fn parse_delay(s: &str) -> Result<Duration, String> {
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.' && c != '-')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let value = number
+        .parse::<f64>()
+        .map_err(|_| format!("Bad delay: {}", s))?;
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("Bad unit in delay {}, use ms, s, m or h", s)),
+    };
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err(format!("Delay must be positive, got {}", s));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+impl Args {
+    /// Parses the command line without the program name, exits with a
+    /// message on bad input.
+    pub fn parse(mut raw_args: impl Iterator<Item = String>) -> Args {
+        let mut format = Format::Text;
+        let mut file: Option<String> = None;
+        let mut prometheus: Option<String> = None;
+        let mut record: Option<String> = None;
+        let mut compress = false;
+        let mut output: Option<String> = None;
+        let mut append = false;
+        let mut replay_file: Option<String> = None;
+        let mut diff_files: Option<(String, String)> = None;
+        let mut baseline: Option<String> = None;
+        let mut mark = false;
+        let mut diff_mark: Option<String> = None;
+        let mut convert: Option<(String, String)> = None;
+        let mut name: Option<String> = None;
+        let mut follow_name = false;
+        let mut cgroup: Option<String> = None;
+        let mut filter: Vec<String> = vec![];
+        let mut events: Vec<&'static str> = vec![];
+        let mut exclude: Vec<String> = vec![];
+        let mut group_by: Option<GroupBy> = None;
+        let mut totals = false;
+        let mut time_format = TimeFormat::Rfc3339;
+        let mut rollup = false;
+        let mut all = false;
+        let mut source = Source::Smaps;
+        let mut layout = Layout::Auto;
+        let mut delta_threshold = 0;
+        let mut leak_window: Option<usize> = None;
+        let mut map_history: Option<usize> = None;
+        let mut avg_window: Option<usize> = None;
+        let mut sort_by: Option<Option<Metric>> = None;
+        let mut top: Option<usize> = None;
+        let mut max_events: Option<usize> = None;
+        let mut quiet = false;
+        let mut once = false;
+        let mut header = false;
+        let mut legend = true;
+        let mut threads = false;
+        let mut summary_interval: Option<u64> = None;
+        let mut heartbeat: Option<Duration> = None;
+        let mut adaptive = false;
+        let mut verbosity = 0;
+        let mut min_interval: Option<Duration> = None;
+        let mut max_interval: Option<Duration> = None;
+        let mut on_signal: Option<i32> = None;
+        let mut show_interval = false;
+        let mut ignore_file: Option<String> = None;
+        let mut ignore_totals = false;
+        let mut hugepage_summary = false;
+        let mut from_to = false;
+        let mut include_unnamed = false;
+        let mut clear_refs = false;
+        let mut watch_top: Option<usize> = None;
+        let mut dirty_only = false;
+        let mut backing: Option<Backing> = None;
+        let mut color: Option<bool> = None; // None is auto
+        let mut alert_rss: Option<u64> = None;
+        let mut alert_pss: Option<u64> = None;
+        let mut alert_swap: Option<u64> = None;
+        let mut alert_growth: Option<f64> = None;
+        let mut alert_growth_metric: Option<Metric> = None;
+        let mut alert_growth_since_start: Option<bool> = None;
+        let mut wx_alert = false;
+        let mut exit_on_alert = false;
+        let mut until_rss: Option<u64> = None;
+        let mut until_map_appears: Option<String> = None;
+        let mut until_map_gone: Option<String> = None;
+        let mut human = false;
+        let mut max_iterations: Option<u64> = None;
+        let mut max_retries = 3;
+        let mut metrics = vec![Metric::Size, Metric::Rss, Metric::Pss, Metric::Swap];
+        let mut args: Vec<String> = vec![];
+        let mut exec = false;
+        let mut command: Option<Vec<String>> = None;
+        while let Some(arg) = raw_args.next() {
+            match &arg[..] {
+                "--format" => {
+                    format = match raw_args.next().as_deref() {
+                        Some("text") => Format::Text,
+                        Some("json") => Format::Json,
+                        Some("ndjson") => Format::Ndjson,
+                        Some("csv") => Format::Csv,
+                        _ => {
+                            eprintln!("--format needs one of: text, json, ndjson, csv");
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--metrics" => {
+                    metrics = match Metric::parse_list(&option_value(&mut raw_args, &arg)) {
+                        Ok(list) => list,
+                        Err(e) => {
+                            eprintln!("{}, known metrics: size, rss, pss, swap, swap_pss, shared_dirty, private_dirty, ksm, lazy_free, referenced", e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--rollup" => rollup = true,
+                "--all" => all = true,
+                "--source" => match &option_value(&mut raw_args, &arg)[..] {
+                    "smaps" => source = Source::Smaps,
+                    "maps" => source = Source::Maps,
+                    "rollup" => rollup = true,
+                    _ => {
+                        eprintln!("--source needs one of: smaps, rollup, maps");
+                        std::process::exit(EXIT_ERROR);
+                    }
+                },
+                "--delta-threshold" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    delta_threshold = match parse_size(&value) {
+                        Ok(size) => size,
+                        Err(e) => {
+                            eprintln!("{} needs a size like 64 or 1M: {}", arg, e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--alert-rss" | "--alert-pss" | "--swap-alert" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    let limit = match parse_size(&value) {
+                        Ok(limit) => Some(limit),
+                        Err(e) => {
+                            eprintln!("{} needs a size like 512M or 2G: {}", arg, e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    };
+                    match &arg[..] {
+                        "--alert-rss" => alert_rss = limit,
+                        "--alert-pss" => alert_pss = limit,
+                        _ => alert_swap = limit,
+                    }
+                }
+                "--alert-growth-pct" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<f64>() {
+                        Ok(percent) if percent > 0.0 && percent.is_finite() => {
+                            alert_growth = Some(percent)
+                        }
+                        _ => {
+                            eprintln!("{} needs a positive percentage, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--alert-growth-metric" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    alert_growth_metric = match &value[..] {
+                        "rss" => Some(Metric::Rss),
+                        "pss" => Some(Metric::Pss),
+                        "swap" => Some(Metric::Swap),
+                        _ => {
+                            eprintln!("{} needs rss, pss or swap, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--alert-growth-since" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    alert_growth_since_start = match &value[..] {
+                        "previous" => Some(false),
+                        "start" => Some(true),
+                        _ => {
+                            eprintln!("{} needs previous or start, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--wx-alert" => wx_alert = true,
+                "--until-rss" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    until_rss = match parse_size(&value) {
+                        Ok(limit) => Some(limit),
+                        Err(e) => {
+                            eprintln!("{} needs a size like 512M or 2G: {}", arg, e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--until-map-appears" => {
+                    until_map_appears = Some(option_value(&mut raw_args, &arg))
+                }
+                "--until-map-gone" => until_map_gone = Some(option_value(&mut raw_args, &arg)),
+                "--exit-on-alert" => exit_on_alert = true,
+                "--sort-by" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    sort_by = match &value[..] {
+                        "address" => Some(None),
+                        _ => match Metric::parse_list(&value) {
+                            Ok(list) if list.len() == 1 => Some(Some(list[0])),
+                            _ => {
+                                eprintln!(
+                                    "--sort-by needs address or a metric like rss, see --metrics"
+                                );
+                                std::process::exit(EXIT_ERROR);
+                            }
+                        },
+                    }
+                }
+                "--top" | "--max-events" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<usize>() {
+                        Ok(n) if arg == "--max-events" => max_events = Some(n),
+                        Ok(n) => top = Some(n),
+                        Err(_) => {
+                            eprintln!("{} needs a non-negative number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--leak-window" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<usize>() {
+                        Ok(n) if n > 0 => leak_window = Some(n),
+                        _ => {
+                            eprintln!("{} needs a positive number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--heartbeat" | "--min-interval" | "--max-interval" => {
+                    let delay = match parse_delay(&option_value(&mut raw_args, &arg)) {
+                        Ok(delay) => Some(delay),
+                        Err(e) => {
+                            eprintln!("{} needs a delay like 10s or 5m: {}", arg, e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    };
+                    match &arg[..] {
+                        "--min-interval" => min_interval = delay,
+                        "--max-interval" => max_interval = delay,
+                        _ => heartbeat = delay,
+                    }
+                }
+                "--adaptive" => adaptive = true,
+                "-v" => verbosity += 1,
+                "-vv" => verbosity += 2,
+                "--on-signal" => {
+                    on_signal = match &option_value(&mut raw_args, &arg)[..] {
+                        "SIGUSR1" | "USR1" => Some(SIGUSR1),
+                        "SIGUSR2" | "USR2" => Some(SIGUSR2),
+                        _ => {
+                            eprintln!("--on-signal needs one of: SIGUSR1, SIGUSR2");
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--summary-interval" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<u64>() {
+                        Ok(n) if n > 0 => summary_interval = Some(n),
+                        _ => {
+                            eprintln!("{} needs a positive number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--map-history" | "--avg-window" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<usize>() {
+                        Ok(n) if n > 1 && arg == "--map-history" => map_history = Some(n),
+                        Ok(n) if n > 1 => avg_window = Some(n),
+                        _ => {
+                            eprintln!("{} needs a number of at least 2, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--totals" => totals = true,
+                "--quiet" | "--no-initial" => quiet = true,
+                "--dirty-only" => dirty_only = true,
+                "--compact" => layout = Layout::Compact,
+                "--verbose" => layout = Layout::Verbose,
+                "--anonymous-only" | "--file-only" => {
+                    let wanted = if arg == "--anonymous-only" {
+                        Backing::Anonymous
+                    } else {
+                        Backing::File
+                    };
+                    if backing.is_some_and(|b| b != wanted) {
+                        eprintln!("--anonymous-only and --file-only exclude each other");
+                        std::process::exit(EXIT_ERROR);
+                    }
+                    backing = Some(wanted);
+                }
+                "--once" => once = true,
+                "--header" => header = true,
+                "--no-legend" => legend = false,
+                "--threads" => threads = true,
+                "--show-interval" => show_interval = true,
+                "--ignore-file" => ignore_file = Some(option_value(&mut raw_args, &arg)),
+                "--ignore-totals" => ignore_totals = true,
+                "--hugepage-summary" => hugepage_summary = true,
+                "--from-to" => from_to = true,
+                "--include-unnamed" => include_unnamed = true,
+                "--clear-refs" => clear_refs = true,
+                "--watch-top" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<usize>() {
+                        Ok(n) if n > 0 => watch_top = Some(n),
+                        _ => {
+                            eprintln!("{} needs a positive number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--color" => {
+                    color = match &option_value(&mut raw_args, &arg)[..] {
+                        "auto" => None,
+                        "always" => Some(true),
+                        "never" => Some(false),
+                        _ => {
+                            eprintln!("--color needs one of: auto, always, never");
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--time-format" => {
+                    time_format = match TimeFormat::parse(&option_value(&mut raw_args, &arg)) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--human" | "-h" => human = true,
+                "--file" => file = Some(option_value(&mut raw_args, &arg)),
+                "--prometheus" => prometheus = Some(option_value(&mut raw_args, &arg)),
+                "--record" => record = Some(option_value(&mut raw_args, &arg)),
+                "--compress" => compress = true,
+                "--output" => output = Some(option_value(&mut raw_args, &arg)),
+                "--append" => append = true,
+                "--replay" => replay_file = Some(option_value(&mut raw_args, &arg)),
+                "--baseline" => baseline = Some(option_value(&mut raw_args, &arg)),
+                "--mark" => mark = true,
+                "--diff-mark" => {
+                    mark = true;
+                    diff_mark = Some(option_value(&mut raw_args, &arg));
+                }
+                "--diff" => {
+                    let old = option_value(&mut raw_args, &arg);
+                    diff_files = Some((old, option_value(&mut raw_args, &arg)));
+                }
+                "--convert" => {
+                    let target = option_value(&mut raw_args, &arg);
+                    if target != "prometheus" && target != "csv" {
+                        eprintln!("--convert needs one of: prometheus, csv");
+                        std::process::exit(EXIT_ERROR);
+                    }
+                    convert = Some((target, option_value(&mut raw_args, &arg)));
+                }
+                "--name" => name = Some(option_value(&mut raw_args, &arg)),
+                "--follow-name" => follow_name = true,
+                "--cgroup" => cgroup = Some(option_value(&mut raw_args, &arg)),
+                "--events" => {
+                    events = match DiffEvent::parse_tags(&option_value(&mut raw_args, &arg)) {
+                        Ok(list) => list,
+                        Err(e) => {
+                            eprintln!("{}, known events: mmap, drop, changed, moved", e);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--filter" => filter.extend(
+                    option_value(&mut raw_args, &arg)
+                        .split(',')
+                        .map(str::to_string),
+                ),
+                "--group-by" => {
+                    group_by = match &option_value(&mut raw_args, &arg)[..] {
+                        "name" => Some(GroupBy::Name),
+                        "type" => Some(GroupBy::Type),
+                        _ => {
+                            eprintln!("--group-by needs one of: name, type");
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--exclude" => exclude.extend(
+                    option_value(&mut raw_args, &arg)
+                        .split(',')
+                        .map(str::to_string),
+                ),
+                "--max-iterations" | "-n" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<u64>() {
+                        Ok(n) => max_iterations = Some(n),
+                        Err(_) => {
+                            eprintln!("{} needs a non-negative number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--max-retries" => {
+                    let value = option_value(&mut raw_args, &arg);
+                    match value.parse::<u32>() {
+                        Ok(n) => max_retries = n,
+                        Err(_) => {
+                            eprintln!("{} needs a non-negative number, got {}", arg, value);
+                            std::process::exit(EXIT_ERROR);
+                        }
+                    }
+                }
+                "--exec" => exec = true,
+                // Everything after it is the command of --exec:
+                "--" => command = Some(raw_args.by_ref().collect()),
+                "--help" => help(),
+                "--version" => {
+                    println!("mapwatcher {}", VERSION);
+                    std::process::exit(0);
+                }
+                // Negative numbers are left for the checks of the positional
+                // arguments:
+                _ if arg.starts_with('-')
+                    && !arg[1..].starts_with(|c: char| c.is_ascii_digit()) =>
+                {
+                    eprintln!("Unknown option {}", arg);
+                    usage();
+                }
+                _ => args.push(arg),
+            }
+        }
+        match (exec, &command) {
+            (true, Some(command)) if !command.is_empty() => {}
+            (false, None) => {}
+            (false, Some(_)) => {
+                eprintln!("-- is only for the command of --exec");
+                std::process::exit(EXIT_ERROR);
+            }
+            (true, _) => {
+                eprintln!("--exec needs a command after --");
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+        if alert_growth.is_none()
+            && (alert_growth_metric.is_some() || alert_growth_since_start.is_some())
+        {
+            eprintln!("--alert-growth-metric and --alert-growth-since need --alert-growth-pct");
+            std::process::exit(EXIT_ERROR);
+        }
+        if append && output.is_none() {
+            eprintln!("--append needs --output");
+            std::process::exit(EXIT_ERROR);
+        }
+        if rollup && source == Source::Maps {
+            eprintln!("--source maps cannot be used with --rollup");
+            std::process::exit(EXIT_ERROR);
+        }
+        // The dirty pages are what --dirty-only is about, so show them:
+        if dirty_only {
+            for metric in [Metric::SharedDirty, Metric::PrivateDirty] {
+                if !metrics.contains(&metric) {
+                    metrics.push(metric);
+                }
+            }
+        }
+        if clear_refs && !metrics.contains(&Metric::Referenced) {
+            metrics.push(Metric::Referenced);
+        }
+        let opts = Options {
+            format,
+            metrics,
+            human,
+            filter,
+            exclude,
+            group_by,
+            totals,
+            time_format,
+            // The largest maps are the interesting ones with --top:
+            sort_by: sort_by.unwrap_or(top.map(|_| Metric::Rss)),
+            top,
+            quiet,
+            dirty_only,
+            watch_top,
+            backing,
+            source,
+            layout,
+            delta_threshold,
+            // Only known once the DELAY argument is parsed:
+            requested_interval: None,
+            events,
+            // Read by main, also again whenever the file changes:
+            ignored: vec![],
+            ignore_totals,
+            hugepage_summary,
+            from_to,
+            working_set: clear_refs,
+            include_unnamed,
+            max_events,
+            // Piped output and --output files stay plain unless asked for:
+            color: color.unwrap_or_else(|| output.is_none() && std::io::stdout().is_terminal()),
+        };
+        Args {
+            opts,
+            log_level: match verbosity {
+                0 => LogLevel::Warn,
+                1 => LogLevel::Info,
+                _ => LogLevel::Debug,
+            },
+            file,
+            prometheus,
+            record,
+            compress,
+            replay_file,
+            diff_files,
+            baseline,
+            adaptive,
+            min_interval,
+            max_interval,
+            mark,
+            diff_mark,
+            convert,
+            name,
+            follow_name,
+            cgroup,
+            rollup,
+            all,
+            exec: command,
+            leak_window,
+            map_history,
+            avg_window,
+            alert_rss,
+            alert_pss,
+            alert_swap,
+            alert_growth,
+            alert_growth_metric: alert_growth_metric.unwrap_or(Metric::Rss),
+            alert_growth_since_start: alert_growth_since_start.unwrap_or(false),
+            wx_alert,
+            exit_on_alert,
+            max_iterations,
+            until_rss,
+            until_map_appears,
+            until_map_gone,
+            max_retries,
+            once,
+            header,
+            legend,
+            threads,
+            clear_refs,
+            summary_interval,
+            heartbeat,
+            on_signal,
+            ignore_file,
+            show_interval,
+            output,
+            append,
+            positional: args,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_delays_with_units() {
+        assert_eq!(parse_delay("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_delay("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_delay("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_delay("0").is_err());
+        assert!(parse_delay("-1").is_err());
+        assert!(parse_delay("1x").is_err());
+    }
+}
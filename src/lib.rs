@@ -0,0 +1,4393 @@
+//! Parsing and diffing of the memory maps of Linux processes as found in
+//! /proc/PID/smaps and /proc/PID/smaps_rollup. This is the library behind
+//! the `mapwatcher` tool, which is only a thin command line wrapper.
+
+use num_format::{Locale, ToFormattedString};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
+use time::{format_description, OffsetDateTime};
+
+// Some information I found on the internet for the fields:
+// See https://utcc.utoronto.ca/~cks/space/blog/linux/SmapsFields?showcomments#comments
+
+// For each VMA mapping that gets listed in smaps, the kernel walks all
+// of the PTEs associated with it and looks at all of the known pages. Each
+// PTE is then counted up:
+//
+//  - the full PTE size is counted as Rss.
+//  - if the page has been used recently, it's added to Referenced.
+//  - if the page is mapped in only one process it is labeled as private;
+//    its full size is added to Pss.
+// - if the page is mapped in more than one process it is shared and the
+//   amount it adds to Pss is divided by the number of processes that have it
+//   mapped.
+//
+// (If the PTE is for something in swap it only adds to the Swap size.)
+//
+// Note that a 'private' page is not quite as private as you might
+// think. Because processes map pages independently of each other, it's
+// possible to have a shared page that is currently mapped only by a single
+// process (eg only one process may have called an obscure libc function
+// recently); such pages are counted in 'private'.
+//
+// The Size of a mapping is how much address space it covers.
+//
+// If the mapping has been locked into memory via mlock() or the like,
+// Locked is the same as Pss (ie, it is this process's fair share of the
+// amount of locked memory for this mapping); otherwise it is 0 kB.
+
+/// The level of a diagnostic message on stderr. Errors and warnings are
+/// shown by default, `-v` adds infos and `-vv` debug messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            LogLevel::Error => "Error",
+            LogLevel::Warn => "Warning",
+            LogLevel::Info => "Info",
+            LogLevel::Debug => "Debug",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+/// Sets the most detailed level which is still logged.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints a diagnostic message to stderr if its level is logged, apart
+/// from the data on stdout, like `log!(Warn, "cannot read {}", path)`.
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log_enabled($crate::LogLevel::$level) {
+            eprintln!("{}: {}", $crate::LogLevel::$level.prefix(), format_args!($($arg)*));
+        }
+    };
+}
+
+/// Output format for snapshots and diffs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Ndjson, // like Json, but every map and diff event on its own line
+    Csv,    // one row per map and sample, no diffs
+}
+
+/// A per map value which can be compared between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Size,
+    Rss,
+    Pss,
+    Swap,
+    SwapPss,
+    SharedDirty,
+    PrivateDirty,
+    Ksm,
+    LazyFree,
+    Referenced,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 10] = [
+        Metric::Size,
+        Metric::Rss,
+        Metric::Pss,
+        Metric::Swap,
+        Metric::SwapPss,
+        Metric::SharedDirty,
+        Metric::PrivateDirty,
+        Metric::Ksm,
+        Metric::LazyFree,
+        Metric::Referenced,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Size => "size",
+            Metric::Rss => "rss",
+            Metric::Pss => "pss",
+            Metric::Swap => "swap",
+            Metric::SwapPss => "swap_pss",
+            Metric::SharedDirty => "shared_dirty",
+            Metric::PrivateDirty => "private_dirty",
+            Metric::Ksm => "ksm",
+            Metric::LazyFree => "lazy_free",
+            Metric::Referenced => "referenced",
+        }
+    }
+
+    pub fn value(&self, m: &Map) -> u64 {
+        match self {
+            Metric::Size => m.size,
+            Metric::Rss => m.rss,
+            Metric::Pss => m.pss,
+            Metric::Swap => m.swap,
+            Metric::SwapPss => m.swap_pss,
+            Metric::SharedDirty => m.shared_dirty,
+            Metric::PrivateDirty => m.private_dirty,
+            Metric::Ksm => m.ksm,
+            Metric::LazyFree => m.lazy_free,
+            Metric::Referenced => m.referenced,
+        }
+    }
+
+    /// Parses a comma separated list like `pss,swap,rss`.
+    pub fn parse_list(s: &str) -> Result<Vec<Metric>, String> {
+        s.split(',')
+            .map(|name| {
+                Metric::ALL
+                    .iter()
+                    .find(|m| m.name() == name)
+                    .copied()
+                    .ok_or_else(|| format!("Unknown metric: {}", name))
+            })
+            .collect()
+    }
+}
+
+/// The file in /proc/PID the maps are read from, chosen with `--source`.
+/// The rollup, the third choice there, has no maps but a `Rollup`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Smaps,
+    Maps, // only the first lines of smaps, much cheaper but without stats
+}
+
+impl Source {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Source::Smaps => "smaps",
+            Source::Maps => "maps",
+        }
+    }
+}
+
+/// How text dumps show the maps, chosen with `--compact` and `--verbose`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Auto,    // compact from COMPACT_FROM maps
+    Compact, // one row per map
+    Verbose, // all fields of a map on several lines
+}
+
+/// From this many shown maps a text dump uses the compact layout unless
+/// `--verbose` is given, since the verbose one no longer fits on a screen.
+pub const COMPACT_FROM: usize = 100;
+
+impl Layout {
+    pub fn is_compact(&self, maps: usize) -> bool {
+        match self {
+            Layout::Auto => maps >= COMPACT_FROM,
+            Layout::Compact => true,
+            Layout::Verbose => false,
+        }
+    }
+}
+
+/// Which kind of maps `--anonymous-only` and `--file-only` keep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backing {
+    Anonymous,
+    File,
+}
+
+/// How maps are collapsed into groups for `--group-by`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupBy {
+    Name, // maps with the same name
+    Type, // the class of anonymous maps, special or file
+}
+
+impl GroupBy {
+    pub fn key(&self, m: &Map) -> String {
+        match self {
+            GroupBy::Name if m.name.is_empty() => "<anon>".to_string(),
+            GroupBy::Name => m.name.clone(),
+            GroupBy::Type => {
+                let kind = if let Some(class) = m.anon_class() {
+                    class.name()
+                } else if m.name.starts_with('[') {
+                    "special"
+                } else {
+                    "file"
+                };
+                kind.to_string()
+            }
+        }
+    }
+}
+
+/// What an anonymous map is used for, to tell which kind of anonymous
+/// memory grows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnonClass {
+    Heap,        // [heap], the main malloc arena
+    Stack,       // [stack], the stack of the main thread
+    ThreadStack, // [stack:TID] on old kernels, MAP_STACK maps on new ones
+    Arena,       // the other malloc arenas, mapped without reserve
+    Mmap,        // any other anonymous mmap
+}
+
+impl AnonClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnonClass::Heap => "heap",
+            AnonClass::Stack => "stack",
+            AnonClass::ThreadStack => "thread stack",
+            AnonClass::Arena => "arena",
+            AnonClass::Mmap => "anon",
+        }
+    }
+}
+
+/// Summed up values of all maps in one group.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GroupStats {
+    pub count: usize,
+    pub size: u64, // all in kB
+    pub rss: u64,
+    pub pss: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+    pub shared_dirty: u64,
+    pub private_dirty: u64,
+    pub ksm: u64,
+    pub lazy_free: u64,
+    pub referenced: u64,
+}
+
+impl GroupStats {
+    pub fn add(&mut self, m: &Map) {
+        self.count += 1;
+        self.size += m.size;
+        self.rss += m.rss;
+        self.pss += m.pss;
+        self.swap += m.swap;
+        self.swap_pss += m.swap_pss;
+        self.shared_dirty += m.shared_dirty;
+        self.private_dirty += m.private_dirty;
+        self.ksm += m.ksm;
+        self.lazy_free += m.lazy_free;
+        self.referenced += m.referenced;
+    }
+
+    pub fn value(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::Size => self.size,
+            Metric::Rss => self.rss,
+            Metric::Pss => self.pss,
+            Metric::Swap => self.swap,
+            Metric::SwapPss => self.swap_pss,
+            Metric::SharedDirty => self.shared_dirty,
+            Metric::PrivateDirty => self.private_dirty,
+            Metric::Ksm => self.ksm,
+            Metric::LazyFree => self.lazy_free,
+            Metric::Referenced => self.referenced,
+        }
+    }
+
+    pub fn to_json(&self, key: &str) -> String {
+        format!(
+            concat!(
+                "{{\"group\":{},\"maps\":{},\"size\":{},\"rss\":{},\"pss\":{},",
+                "\"swap\":{},\"swap_pss\":{},\"shared_dirty\":{},\"private_dirty\":{},",
+                "\"ksm\":{},\"lazy_free\":{},\"referenced\":{}}}"
+            ),
+            json_string(key),
+            self.count,
+            self.size,
+            self.rss,
+            self.pss,
+            self.swap,
+            self.swap_pss,
+            self.shared_dirty,
+            self.private_dirty,
+            self.ksm,
+            self.lazy_free,
+            self.referenced
+        )
+    }
+}
+
+pub fn group_maps<'a>(
+    maps: impl IntoIterator<Item = &'a Map>,
+    by: GroupBy,
+) -> HashMap<String, GroupStats> {
+    let mut groups: HashMap<String, GroupStats> = HashMap::new();
+    for m in maps {
+        groups.entry(by.key(m)).or_default().add(m);
+    }
+    groups
+}
+
+/// Returns the group keys of both snapshots in sorted order.
+pub fn sorted_keys(
+    a: &HashMap<String, GroupStats>,
+    b: &HashMap<String, GroupStats>,
+) -> Vec<String> {
+    let mut keys: Vec<String> = a.keys().chain(b.keys()).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Settings which influence how snapshots and diffs are shown.
+pub struct Options {
+    pub format: Format,
+    pub metrics: Vec<Metric>,
+    pub human: bool,
+    pub filter: Vec<String>, // show only maps whose name contains one of these
+    pub exclude: Vec<String>, // hide maps whose name contains one of these
+    pub group_by: Option<GroupBy>,
+    pub totals: bool, // print a totals line after each diff
+    pub time_format: TimeFormat,
+    pub sort_by: Option<Metric>, // largest first, None keeps the address order
+    pub top: Option<usize>,      // show only this many maps or changes
+    pub quiet: bool,             // skip the dump of the initial maps
+    pub color: bool,             // color the event tags of text diffs
+    pub dirty_only: bool,        // only show changes of the dirty pages
+    pub watch_top: Option<usize>, // show the largest maps instead of diffs
+    pub backing: Option<Backing>, // show only anonymous or file-backed maps
+    pub source: Source,
+    pub layout: Layout,
+    pub delta_threshold: u64, // in kB, smaller changes of a map are not shown
+    pub requested_interval: Option<Duration>, // show the measured interval next to it
+    pub events: Vec<&'static str>, // the tags of the diff events to show, empty shows all
+    pub ignored: Vec<String>, // the patterns of --ignore-file, see `matches_pattern`
+    pub ignore_totals: bool,  // leave ignored maps out of the totals, too
+    pub hugepage_summary: bool, // add the huge page totals to totals and diffs
+    pub from_to: bool,        // show every event as old -> new (delta)
+    pub working_set: bool,    // referenced counts since the last sample, --clear-refs
+    pub include_unnamed: bool, // also report the events of maps without a name
+    pub max_events: Option<usize>, // the most events of a diff, the largest changes
+}
+
+impl Options {
+    /// Decides if a map is displayed, totals always include all maps.
+    pub fn shows(&self, m: &Map) -> bool {
+        (self.filter.is_empty() || self.filter.iter().any(|f| m.name.contains(&f[..])))
+            && !self.exclude.iter().any(|e| m.name.contains(&e[..]))
+            && !self.ignores(m)
+            && match self.backing {
+                Some(Backing::Anonymous) => m.is_anonymous(),
+                Some(Backing::File) => !m.is_anonymous(),
+                None => true,
+            }
+    }
+
+    /// Tells if a map matches one of the patterns of `--ignore-file`.
+    pub fn ignores(&self, m: &Map) -> bool {
+        self.ignored.iter().any(|p| matches_pattern(&m.name, p))
+    }
+
+    /// Describes the time measured between two samples together with the
+    /// requested delay, like `interval: 1.03s (requested 1.00s)`, or
+    /// nothing without `--show-interval`.
+    pub fn interval(&self, from: SystemTime, to: SystemTime) -> Option<String> {
+        let requested = self.requested_interval?;
+        let measured = to.duration_since(from).unwrap_or_default();
+        Some(format!(
+            "interval: {:.2}s (requested {:.2}s)",
+            measured.as_secs_f64(),
+            requested.as_secs_f64()
+        ))
+    }
+
+    /// The same as JSON fields with a leading comma, in seconds.
+    fn interval_json(&self, from: SystemTime, to: SystemTime) -> String {
+        match self.requested_interval {
+            Some(requested) => format!(
+                ",\"interval\":{:.3},\"requested_interval\":{:.3}",
+                to.duration_since(from).unwrap_or_default().as_secs_f64(),
+                requested.as_secs_f64()
+            ),
+            None => "".to_string(),
+        }
+    }
+
+    /// Returns the events of a diff to display in the order to display
+    /// them in, like `select` for maps.
+    pub fn shown_events(&self, events: &[DiffEvent]) -> Vec<DiffEvent> {
+        let opts = self;
+        let mut changes: Vec<DiffEvent> = events
+            .iter()
+            .filter(|event| {
+                let m = event.map();
+                (opts.include_unnamed || !m.name.is_empty())
+                    && opts.shows(m)
+                    && (opts.events.is_empty() || opts.events.contains(&event.tag()))
+                    && match event {
+                        DiffEvent::Changed { new, prev } if opts.dirty_only => {
+                            new.shared_dirty != prev.shared_dirty
+                                || new.private_dirty != prev.private_dirty
+                        }
+                        DiffEvent::Changed { new, prev } => new.shows_change(prev, opts),
+                        _ => !opts.dirty_only || m.has_dirty_pages(),
+                    }
+            })
+            .cloned()
+            .collect();
+        if let Some(metric) = opts.sort_by {
+            changes.sort_by_key(|event| std::cmp::Reverse(event.delta(metric).unsigned_abs()));
+        }
+        if let Some(top) = opts.top {
+            changes.truncate(top);
+        }
+        changes
+    }
+
+    /// Returns the maps to display in the order to display them in.
+    pub fn select<'a>(&self, maps: &'a [Map]) -> Vec<&'a Map> {
+        let mut selected: Vec<&Map> = maps.iter().filter(|m| self.shows(m)).collect();
+        if let Some(metric) = self.sort_by {
+            selected.sort_by_key(|m| std::cmp::Reverse(metric.value(m)));
+        }
+        if let Some(top) = self.top {
+            selected.truncate(top);
+        }
+        selected
+    }
+}
+
+/// Formats a size given in kB, either raw or in the largest binary unit
+/// in which the value is at least 1, like `512.0 MiB`.
+pub fn format_kb(kb: u64, human: bool) -> String {
+    if !human {
+        return kb.to_string();
+    }
+    let units = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = kb as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Parses a size like `512`, `64M`, `2G` or `1.5GiB` into kB. Units are
+/// binary, a plain number is taken as kB like everywhere else.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let value = number
+        .parse::<f64>()
+        .map_err(|_| format!("Bad size: {}", s))?;
+    let factor = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" | "k" | "K" => 1.0,
+        "m" | "M" => 1024.0,
+        "g" | "G" => 1024.0 * 1024.0,
+        "t" | "T" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("Bad unit in size: {}", s)),
+    };
+    Ok((value * factor) as u64)
+}
+
+/// Like `format_kb` but for signed differences, in human mode with sign.
+pub fn format_kb_diff(diff: i64, human: bool) -> String {
+    if !human {
+        return diff.to_string();
+    }
+    let sign = if diff < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_kb(diff.unsigned_abs(), true))
+}
+
+/// Formats a rate of change in kB per second, always with sign.
+pub fn format_rate(kb_per_sec: f64, human: bool) -> String {
+    if human {
+        format!("{}/s", format_kb_diff(kb_per_sec.round() as i64, true))
+    } else {
+        format!("{:+.0} kB/s", kb_per_sec)
+    }
+}
+
+/// Formats a total, with thousands separators unless in human mode.
+pub fn format_total(kb: u64, human: bool) -> String {
+    if human {
+        format_kb(kb, true)
+    } else {
+        kb.to_formatted_string(&Locale::en)
+    }
+}
+
+/// Colors the tag of a text diff line like `MMAP` by its kind, if colors
+/// are wanted: new things green, vanished ones red, changes yellow.
+pub fn colored_tag(tag: &str, opts: &Options) -> String {
+    let code = match tag {
+        "MMAP" | "NEW" => "32",
+        "DROP" | "GONE" => "31",
+        "CHANGED" => "33",
+        "MOVED" => "36",
+        _ => "",
+    };
+    if opts.color && !code.is_empty() {
+        format!("\x1b[{}m{}\x1b[0m", code, tag)
+    } else {
+        tag.to_string()
+    }
+}
+
+/// From this share of the rss on lazy_free pages are pointed out.
+pub const LAZY_FREE_NOTE_PERCENT: f64 = 10.0;
+
+/// The share of `part` in `total` in percent, 0 for an empty total.
+pub fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Matches a map name against a pattern of `--ignore-file`. A pattern
+/// with `*` (any characters) or `?` (one character) is a glob for the
+/// whole name, any other one a substring.
+pub fn matches_pattern(name: &str, pattern: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return name.contains(pattern);
+    }
+    let name: Vec<char> = name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    // The position after the last `*` and the name position it matched
+    // up to, to backtrack to when the rest does not match:
+    let mut star: Option<(usize, usize)> = None;
+    let (mut n, mut p) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            n += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some((after_star, matched)) = star {
+            p = after_star;
+            n = matched + 1;
+            star = Some((after_star, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parses the contents of an `--ignore-file`, one pattern per line.
+/// Empty lines and lines starting with `#` are skipped.
+pub fn parse_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Formats a duration for people, like `42.5s`, `12m 5s` or `3h 2m`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", d.as_secs_f64())
+    } else if secs < 3600 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+/// Formats the age of a map like `format_duration`, but without spaces,
+/// like `12m5s`, so that it stays one column.
+pub fn format_age(d: Duration) -> String {
+    format_duration(d).replace(' ', "")
+}
+
+/// Draws the values as a line of block characters from low to high,
+/// scaled between the smallest and the largest value.
+pub fn sparkline(values: &[usize]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|&v| {
+            if max == min {
+                BLOCKS[0]
+            } else {
+                BLOCKS[(v - min) * (BLOCKS.len() - 1) / (max - min)]
+            }
+        })
+        .collect()
+}
+
+/// Quotes and escapes a string for use in JSON output.
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Quotes a CSV field if needed, doubling any quotes in it.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) || s.trim() != s {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Escapes a Prometheus label value, which is always double quoted.
+pub fn prometheus_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Starts every snapshot in a recording made with `--record`.
+pub const RECORD_HEADER: &str = "# mapwatcher snapshot";
+
+pub const CSV_HEADER: &str = concat!(
+    "timestamp,pid,start,end,flags,offset,device,inode,name,size,rss,pss,",
+    "shared_clean,shared_dirty,private_clean,private_dirty,referenced,anonymous,ksm,",
+    "lazy_free,anon_huge_pages,swap,swap_pss,locked,vmflags"
+);
+
+/// How timestamps are printed, chosen with `--time-format`.
+pub enum TimeFormat {
+    Rfc3339,
+    Unix, // seconds since the epoch, with milliseconds
+    Custom(format_description::OwnedFormatItem),
+}
+
+impl TimeFormat {
+    /// Accepts `rfc3339`, `unix` or a pattern in the syntax of the `time`
+    /// crate like `[hour]:[minute]:[second]`.
+    pub fn parse(s: &str) -> Result<TimeFormat, String> {
+        match s {
+            "rfc3339" => Ok(TimeFormat::Rfc3339),
+            "unix" => Ok(TimeFormat::Unix),
+            _ => format_description::parse_owned::<2>(s)
+                .map(TimeFormat::Custom)
+                .map_err(|e| format!("Invalid time format {}: {}", s, e)),
+        }
+    }
+
+    pub fn format(&self, t: SystemTime) -> String {
+        let odt: OffsetDateTime = t.into();
+        match self {
+            TimeFormat::Rfc3339 => odt
+                .format(&format_description::well_known::Rfc3339)
+                .unwrap(),
+            TimeFormat::Unix => {
+                let d = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                format!("{}.{:03}", d.as_secs(), d.subsec_millis())
+            }
+            TimeFormat::Custom(items) => odt
+                .format(items)
+                .unwrap_or_else(|e| format!("<bad time: {}>", e)),
+        }
+    }
+}
+
+/// Why the maps or other information of a process could not be read,
+/// so that callers can tell a process which exited from missing
+/// privileges or a file which makes no sense.
+#[derive(Debug)]
+pub enum MapError {
+    ProcessGone(i32),         // the process exited or never existed
+    PermissionDenied(String), // the path which we may not access
+    Parse { line: String, reason: String },
+    Io { path: String, error: io::Error },
+    Other(String), // like a failing gzip or an ambiguous process name
+}
+
+impl MapError {
+    /// Classifies the failed access to `path`, lacking the permission is
+    /// told apart from other errors.
+    pub fn io(path: &str, error: io::Error) -> MapError {
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            MapError::PermissionDenied(path.to_string())
+        } else {
+            MapError::Io {
+                path: path.to_string(),
+                error,
+            }
+        }
+    }
+
+    /// The same for a file in /proc/PID, which fails when the process
+    /// `pid` exits before or while it is read.
+    pub fn of_process(pid: i32, path: &str, error: io::Error) -> MapError {
+        if process_exists(pid) {
+            MapError::io(path, error)
+        } else {
+            MapError::ProcessGone(pid)
+        }
+    }
+
+    fn parse(line: &str, reason: impl Into<String>) -> MapError {
+        MapError::Parse {
+            line: line.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::ProcessGone(pid) => write!(f, "Process {} is gone", pid),
+            MapError::PermissionDenied(path) => write!(f, "Permission denied for {}", path),
+            MapError::Parse { line, reason } => write!(f, "{}: {}", reason, line),
+            MapError::Io { path, error } => write!(f, "Cannot access {}: {}", path, error),
+            MapError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Map {
+    pub start: u64,
+    pub end: u64,
+    pub flags: String,
+    pub offset: u64,       // in the backing file, 0 for anonymous maps
+    pub device_major: u32, // of the device holding the backing file
+    pub device_minor: u32,
+    pub inode: u64, // of the backing file, 0 for anonymous maps
+    pub name: String,
+    pub size: u64, // all in kB
+    pub kernel_page_size: u64,
+    pub mmu_page_size: u64,
+    pub rss: u64,
+    pub pss: u64,
+    pub shared_clean: u64,
+    pub shared_dirty: u64,
+    pub private_clean: u64,
+    pub private_dirty: u64,
+    pub referenced: u64,
+    pub anonymous: u64,
+    pub ksm: u64, // pages merged with identical ones by KSM
+    pub lazy_free: u64,
+    pub anon_huge_pages: u64,
+    pub shmem_pmd_mapped: u64,
+    pub file_pmd_mapped: u64,
+    pub shared_huge_tlb: u64,
+    pub private_huge_tlb: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+    pub locked: u64,
+    pub thp_eligible: bool,
+    pub protection_key: u64,
+    pub vmflags: String,             // the raw line, for display
+    pub vmflag_set: HashSet<String>, // the two letter flags like "rd" or "ex"
+}
+
+impl Map {
+    /// Parses the first line of a map in smaps, which is also the whole
+    /// line in /proc/PID/maps, like
+    /// `7f2c4a1e2000-7f2c4a208000 r--p 00000000 08:01 1234 /usr/lib/libc.so.6`.
+    /// All fields from the following lines are left at 0.
+    pub fn parse_first_line(first_line: &str) -> Result<Map, MapError> {
+        let items: Vec<String> = first_line.split_whitespace().map(str::to_string).collect();
+        let Some(range) = items.first() else {
+            return Err(MapError::parse(first_line, "Found no bounds"));
+        };
+        let bounds: Vec<String> = range.split("-").map(str::to_string).collect();
+        if bounds.len() != 2 {
+            return Err(MapError::parse(first_line, "Found bad bounds"));
+        }
+        let get_hex = |s: &String| -> Result<u64, MapError> {
+            u64::from_str_radix(s, 16).map_err(|e| MapError::parse(first_line, e.to_string()))
+        };
+        // Special regions like [vsyscall] can lack columns in front of
+        // their name. Columns which are missing or odd are taken as 0 with
+        // a warning, rather than giving up on the whole snapshot:
+        let name_at = items
+            .iter()
+            .position(|item| item.starts_with('['))
+            .unwrap_or(items.len())
+            .min(5);
+        let columns = &items[..name_at];
+        let offset = columns.get(2).and_then(|s| get_hex(s).ok());
+        let devices = columns
+            .get(3)
+            .and_then(|s| s.split_once(':'))
+            .and_then(|(major, minor)| {
+                Some((
+                    u32::from_str_radix(major, 16).ok()?,
+                    u32::from_str_radix(minor, 16).ok()?,
+                ))
+            });
+        let inode = columns.get(4).and_then(|s| s.parse::<u64>().ok());
+        if offset.is_none() || devices.is_none() || inode.is_none() {
+            log!(
+                Warn,
+                "odd first line of a map, taking missing columns as 0: {}",
+                first_line
+            );
+        }
+        // The name is the rest of the line as it is, so that paths with
+        // spaces in them survive:
+        let mut rest = first_line.trim_start();
+        for _ in 0..name_at {
+            rest = rest
+                .trim_start_matches(|c: char| !c.is_whitespace())
+                .trim_start();
+        }
+        let name = rest.trim_end().to_string();
+        let (device_major, device_minor) = devices.unwrap_or_default();
+        Ok(Map {
+            start: get_hex(&bounds[0])?,
+            end: get_hex(&bounds[1])?,
+            flags: columns.get(1).cloned().unwrap_or_default(),
+            offset: offset.unwrap_or_default(),
+            device_major,
+            device_minor,
+            inode: inode.unwrap_or_default(),
+            name,
+            ..Default::default()
+        })
+    }
+
+    /// Parses one complete entry of smaps, from its first line up to
+    /// and including the VmFlags line, which some kernels do not have.
+    /// An empty entry gives `None`.
+    pub fn parse(entry: &str) -> Result<Option<Map>, MapError> {
+        let mut lines = entry.lines();
+        let map = Map::parse_lines(&mut lines)?;
+        if let Some(extra) = lines.find(|line| !line.trim().is_empty()) {
+            return Err(MapError::parse(
+                extra,
+                "Unexpected line after the end of a map",
+            ));
+        }
+        Ok(map)
+    }
+
+    /// Takes the next entry from the lines of a whole smaps file and
+    /// parses it with `Map::parse`.
+    pub fn parse_from_line_iterator(lines: &mut std::str::Lines) -> Result<Option<Map>, MapError> {
+        let mut entry = String::new();
+        while let Some(line) = Map::next_entry_line(lines, entry.is_empty()) {
+            entry.push_str(line);
+            entry.push('\n');
+            if line.starts_with("VmFlags") {
+                break;
+            }
+        }
+        Map::parse(&entry)
+    }
+
+    /// Takes the next line of the current entry, `None` at the end of the
+    /// lines or at the first line of the next entry, which is left alone.
+    /// Without a VmFlags line that is how the end of an entry is found.
+    fn next_entry_line<'a>(lines: &mut std::str::Lines<'a>, first: bool) -> Option<&'a str> {
+        let mut ahead = lines.clone();
+        let line = ahead.next()?;
+        if !first && Map::starts_entry(line) {
+            return None;
+        }
+        *lines = ahead;
+        Some(line)
+    }
+
+    /// Tells if `line` is the first line of an entry, which starts with an
+    /// address range like `7f2c4a1e2000-7f2c4a208000`.
+    fn starts_entry(line: &str) -> bool {
+        let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        line.split_whitespace()
+            .next()
+            .and_then(|range| range.split_once('-'))
+            .is_some_and(|(start, end)| is_hex(start) && is_hex(end))
+    }
+
+    fn parse_lines(lines: &mut std::str::Lines) -> Result<Option<Map>, MapError> {
+        // Blank lines between entries, as in hand edited files, are skipped:
+        let Some(first_line) = lines.find(|line| !line.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let mut map = Map::parse_first_line(first_line)?;
+        let range = format!("{:x}-{:x}", map.start, map.end);
+        let get_number = |s: &String| -> Result<u64, MapError> {
+            let parts: Vec<String> = s.split_whitespace().map(str::to_string).collect();
+            if parts.len() < 2 {
+                return Ok(0);
+            }
+            parts[1].parse::<u64>().map_err(|_| {
+                MapError::parse(
+                    s,
+                    format!("Expecting a number in second place in map {}", range),
+                )
+            })
+        };
+        // The set and order of the fields differs between kernel versions,
+        // so we go by the keys and leave unknown or missing fields at 0:
+        while let Some(line) = Map::next_entry_line(lines, false) {
+            let line = line.to_string();
+            if line.starts_with("VmFlags") {
+                map.vmflag_set = line
+                    .split_whitespace()
+                    .skip(1)
+                    .map(str::to_string)
+                    .collect();
+                map.vmflags = line;
+                break;
+            }
+            let field = match line.split(':').next().unwrap_or_default() {
+                "Size" => &mut map.size,
+                "KernelPageSize" => &mut map.kernel_page_size,
+                "MMUPageSize" => &mut map.mmu_page_size,
+                "Rss" => &mut map.rss,
+                "Pss" => &mut map.pss,
+                "Shared_Clean" => &mut map.shared_clean,
+                "Shared_Dirty" => &mut map.shared_dirty,
+                "Private_Clean" => &mut map.private_clean,
+                "Private_Dirty" => &mut map.private_dirty,
+                "Referenced" => &mut map.referenced,
+                "Anonymous" => &mut map.anonymous,
+                "KSM" | "Ksm" => &mut map.ksm,
+                "LazyFree" => &mut map.lazy_free,
+                "AnonHugePages" => &mut map.anon_huge_pages,
+                "ShmemPmdMapped" => &mut map.shmem_pmd_mapped,
+                "FilePmdMapped" => &mut map.file_pmd_mapped,
+                "Shared_Hugetlb" => &mut map.shared_huge_tlb,
+                "Private_Hugetlb" => &mut map.private_huge_tlb,
+                "Swap" => &mut map.swap,
+                "SwapPss" => &mut map.swap_pss,
+                "Locked" => &mut map.locked,
+                "ProtectionKey" => &mut map.protection_key,
+                "THPeligible" => {
+                    map.thp_eligible = get_number(&line)? != 0;
+                    continue;
+                }
+                _ => continue,
+            };
+            *field = get_number(&line)?;
+        }
+        Ok(Some(map))
+    }
+
+    /// Writes the map back in smaps format, leaving out the fields which
+    /// are 0 since the parser defaults missing fields to 0 anyway.
+    pub fn to_smaps(&self) -> String {
+        let mut res = format!(
+            "{:x}-{:x} {} {:08x} {:02x}:{:02x} {} {}\n",
+            self.start,
+            self.end,
+            self.flags,
+            self.offset,
+            self.device_major,
+            self.device_minor,
+            self.inode,
+            self.name
+        );
+        let fields = [
+            ("Size", self.size),
+            ("KernelPageSize", self.kernel_page_size),
+            ("MMUPageSize", self.mmu_page_size),
+            ("Rss", self.rss),
+            ("Pss", self.pss),
+            ("Shared_Clean", self.shared_clean),
+            ("Shared_Dirty", self.shared_dirty),
+            ("Private_Clean", self.private_clean),
+            ("Private_Dirty", self.private_dirty),
+            ("Referenced", self.referenced),
+            ("Anonymous", self.anonymous),
+            ("KSM", self.ksm),
+            ("LazyFree", self.lazy_free),
+            ("AnonHugePages", self.anon_huge_pages),
+            ("ShmemPmdMapped", self.shmem_pmd_mapped),
+            ("FilePmdMapped", self.file_pmd_mapped),
+            ("Shared_Hugetlb", self.shared_huge_tlb),
+            ("Private_Hugetlb", self.private_huge_tlb),
+            ("Swap", self.swap),
+            ("SwapPss", self.swap_pss),
+            ("Locked", self.locked),
+        ];
+        for (key, value) in fields.iter().filter(|(_, value)| *value != 0) {
+            res.push_str(&format!("{}: {} kB\n", key, value));
+        }
+        if self.thp_eligible {
+            res.push_str("THPeligible: 1\n");
+        }
+        if self.protection_key != 0 {
+            res.push_str(&format!("ProtectionKey: {}\n", self.protection_key));
+        }
+        // Entries parse without VmFlags, a map which had none keeps none:
+        if !self.vmflags.is_empty() {
+            res.push_str(&self.vmflags);
+            res.push('\n');
+        }
+        res
+    }
+
+    pub fn pretty_print(&self, human: bool) {
+        let kb = |v: u64| format_kb(v, human);
+        println!("Range: {:x}-{:x}", self.start, self.end);
+        let anonymous = if self.inode == 0 { " (anonymous)" } else { "" };
+        println!(
+            "Flags: {}, Offset: {:#x}, device: {:x}:{:x}, Inode: {}{}",
+            self.flags, self.offset, self.device_major, self.device_minor, self.inode, anonymous
+        );
+        println!("Name: {}", self.name);
+        println!(
+            "Kernel page size: {}, mmu page size: {}",
+            kb(self.kernel_page_size),
+            kb(self.mmu_page_size)
+        );
+        println!(
+            "Size: {}, Rss: {}, Pss: {}",
+            kb(self.size),
+            kb(self.rss),
+            kb(self.pss)
+        );
+        println!(
+            "Shared clean: {}, shared dirty: {}, private clean: {}, private dirty: {}",
+            kb(self.shared_clean),
+            kb(self.shared_dirty),
+            kb(self.private_clean),
+            kb(self.private_dirty)
+        );
+        println!(
+            "Referenced: {}, anonymous: {}, ksm: {}, lazy free: {}",
+            kb(self.referenced),
+            kb(self.anonymous),
+            kb(self.ksm),
+            kb(self.lazy_free)
+        );
+        println!(
+            "Anon huge pages: {}, shmem pmd mapped: {}, file pmd mapped: {}",
+            kb(self.anon_huge_pages),
+            kb(self.shmem_pmd_mapped),
+            kb(self.file_pmd_mapped)
+        );
+        println!(
+            "Shared huge tlb: {}, private huge tlb: {}, swap: {}, swap pss: {}, locked: {}",
+            kb(self.shared_huge_tlb),
+            kb(self.private_huge_tlb),
+            kb(self.swap),
+            kb(self.swap_pss),
+            kb(self.locked)
+        );
+        println!(
+            "Thp eligible: {}, protection key: {}, vmflags: {}\n",
+            self.thp_eligible, self.protection_key, self.vmflags
+        );
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"range\":\"{:x}-{:x}\",\"flags\":{},\"offset\":{},\"device\":\"{:x}:{:x}\",",
+                "\"inode\":{},\"name\":{},\"size\":{},\"kernel_page_size\":{},",
+                "\"mmu_page_size\":{},\"rss\":{},\"pss\":{},\"shared_clean\":{},",
+                "\"shared_dirty\":{},\"private_clean\":{},\"private_dirty\":{},",
+                "\"referenced\":{},\"anonymous\":{},\"ksm\":{},\"lazy_free\":{},",
+                "\"anon_huge_pages\":{},",
+                "\"shmem_pmd_mapped\":{},\"file_pmd_mapped\":{},\"shared_huge_tlb\":{},",
+                "\"private_huge_tlb\":{},\"swap\":{},\"swap_pss\":{},\"locked\":{},",
+                "\"thp_eligible\":{},\"protection_key\":{},\"vmflags\":{}}}"
+            ),
+            self.start,
+            self.end,
+            json_string(&self.flags),
+            self.offset,
+            self.device_major,
+            self.device_minor,
+            self.inode,
+            json_string(&self.name),
+            self.size,
+            self.kernel_page_size,
+            self.mmu_page_size,
+            self.rss,
+            self.pss,
+            self.shared_clean,
+            self.shared_dirty,
+            self.private_clean,
+            self.private_dirty,
+            self.referenced,
+            self.anonymous,
+            self.ksm,
+            self.lazy_free,
+            self.anon_huge_pages,
+            self.shmem_pmd_mapped,
+            self.file_pmd_mapped,
+            self.shared_huge_tlb,
+            self.private_huge_tlb,
+            self.swap,
+            self.swap_pss,
+            self.locked,
+            self.thp_eligible,
+            self.protection_key,
+            json_string(&self.vmflags),
+        )
+    }
+
+    /// One CSV row in the column order of `CSV_HEADER`.
+    pub fn to_csv(&self, timestamp: &str, pid: i32) -> String {
+        format!(
+            "{},{},{:x},{:x},{},{},{:x}:{:x},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            timestamp,
+            pid,
+            self.start,
+            self.end,
+            self.flags,
+            self.offset,
+            self.device_major,
+            self.device_minor,
+            self.inode,
+            csv_field(&self.name),
+            self.size,
+            self.rss,
+            self.pss,
+            self.shared_clean,
+            self.shared_dirty,
+            self.private_clean,
+            self.private_dirty,
+            self.referenced,
+            self.anonymous,
+            self.ksm,
+            self.lazy_free,
+            self.anon_huge_pages,
+            self.swap,
+            self.swap_pss,
+            self.locked,
+            csv_field(self.vmflags.trim_start_matches("VmFlags:").trim())
+        )
+    }
+
+    /// Reports a new (MMAP) or vanished (DROP) map, either directly as a
+    /// text line or by collecting a JSON object in `events`.
+    pub fn print_event(&self, kind: &str, opts: &Options, events: &mut Vec<String>) {
+        match opts.format {
+            Format::Text => println!(
+                "{}: {:x}-{:x} size={} rss={} {}",
+                colored_tag(kind, opts),
+                self.start,
+                self.end,
+                format_kb(self.size, opts.human),
+                format_kb(self.rss, opts.human),
+                self.labeled_name()
+            ),
+            Format::Json | Format::Ndjson => events.push(format!(
+                "{{\"event\":\"{}\",\"range\":\"{:x}-{:x}\",\"size\":{},\"rss\":{},\"name\":{}{}}}",
+                kind,
+                self.start,
+                self.end,
+                self.size,
+                self.rss,
+                json_string(&self.name),
+                self.class_json()
+            )),
+            Format::Csv => {}
+        }
+    }
+
+    /// Reports a map which was moved from the range of `prev` to a new one.
+    pub fn print_move(&self, prev: &Map, opts: &Options, events: &mut Vec<String>) {
+        match opts.format {
+            Format::Text => {
+                let size_diff = if self.size != prev.size {
+                    format!(" (was {})", format_kb(prev.size, opts.human))
+                } else {
+                    "".to_string()
+                };
+                println!(
+                    "{}: {:x}-{:x} to {:x}-{:x} size={}{} rss={} {}",
+                    colored_tag("MOVED", opts),
+                    prev.start,
+                    prev.end,
+                    self.start,
+                    self.end,
+                    format_kb(self.size, opts.human),
+                    size_diff,
+                    format_kb(self.rss, opts.human),
+                    self.labeled_name()
+                )
+            }
+            Format::Json | Format::Ndjson => events.push(format!(
+                concat!(
+                    "{{\"event\":\"MOVED\",\"range\":\"{:x}-{:x}\",\"prev_range\":\"{:x}-{:x}\",",
+                    "\"size\":{},\"prev_size\":{},\"rss\":{},\"prev_rss\":{},\"name\":{}{}}}"
+                ),
+                self.start,
+                self.end,
+                prev.start,
+                prev.end,
+                self.size,
+                prev.size,
+                self.rss,
+                prev.rss,
+                json_string(&self.name),
+                self.class_json()
+            )),
+            Format::Csv => {}
+        }
+    }
+
+    /// Tells if `self` is probably `prev` mapped at a new address, that is
+    /// it has the same name and permissions and a similar size.
+    pub fn looks_moved_from(&self, prev: &Map) -> bool {
+        !self.name.is_empty()
+            && self.name == prev.name
+            && self.flags == prev.flags
+            && self.size.min(prev.size) * 4 >= self.size.max(prev.size) * 3
+    }
+
+    /// Tells if no file backs the map, like the heap, the stack or an
+    /// unnamed map. Files on special file systems like memfds count as
+    /// files since they have an inode.
+    pub fn is_anonymous(&self) -> bool {
+        self.inode == 0 && (self.name.is_empty() || self.name.starts_with('['))
+    }
+
+    /// Classifies an anonymous map by its name and VmFlags, `None` for
+    /// file-backed and special maps like `[vdso]`. Thread stacks are
+    /// mapped with MAP_STACK, which newer kernels show as `nh`, malloc
+    /// arenas are reserved with MAP_NORESERVE, shown as `nr`.
+    pub fn anon_class(&self) -> Option<AnonClass> {
+        if self.inode != 0 {
+            return None;
+        }
+        if self.name.starts_with("[heap]") {
+            Some(AnonClass::Heap)
+        } else if self.name.starts_with("[stack:") {
+            Some(AnonClass::ThreadStack)
+        } else if self.name.starts_with("[stack]") || self.has_vmflag("gd") {
+            Some(AnonClass::Stack)
+        } else if !self.name.is_empty() {
+            None
+        } else if self.has_vmflag("nh") {
+            Some(AnonClass::ThreadStack)
+        } else if self.has_vmflag("nr") {
+            Some(AnonClass::Arena)
+        } else {
+            Some(AnonClass::Mmap)
+        }
+    }
+
+    /// The name of a map for event lines, with the class of an anonymous
+    /// map appended like `[stack:1234] (thread stack)`. Maps without a
+    /// name are labeled `<anon>`.
+    fn labeled_name(&self) -> String {
+        let name = if self.name.is_empty() {
+            "<anon>"
+        } else {
+            &self.name
+        };
+        match self.anon_class() {
+            Some(class) => format!("{} ({})", name, class.name()),
+            None => name.to_string(),
+        }
+    }
+
+    /// The class of an anonymous map as a JSON field with a leading comma.
+    fn class_json(&self) -> String {
+        match self.anon_class() {
+            Some(class) => format!(",\"class\":{}", json_string(class.name())),
+            None => "".to_string(),
+        }
+    }
+
+    /// Tells if the map can be written and executed at the same time,
+    /// by its permissions or its VmFlags, which hardened programs avoid.
+    pub fn is_writable_executable(&self) -> bool {
+        (self.flags.contains('w') && self.flags.contains('x'))
+            || (self.has_vmflag("wr") && self.has_vmflag("ex"))
+    }
+
+    pub fn has_dirty_pages(&self) -> bool {
+        self.total_dirty() != 0
+    }
+
+    /// The resident pages shared with other processes, clean or dirty.
+    pub fn total_shared(&self) -> u64 {
+        self.shared_clean + self.shared_dirty
+    }
+
+    /// The resident pages only this process uses, clean or dirty.
+    pub fn total_private(&self) -> u64 {
+        self.private_clean + self.private_dirty
+    }
+
+    /// The modified pages, which cannot simply be dropped by the kernel.
+    pub fn total_dirty(&self) -> u64 {
+        self.shared_dirty + self.private_dirty
+    }
+
+    /// Tells if any of the compared metrics, the end, the permissions or
+    /// the VmFlags differ between `prev` and `self`. Metrics and the end
+    /// have to change by at least `opts.delta_threshold` kB.
+    pub fn shows_change(&self, prev: &Map, opts: &Options) -> bool {
+        let big = |diff: u64| diff > 0 && diff >= opts.delta_threshold;
+        big(self.end.abs_diff(prev.end).div_ceil(1024))
+            || self
+                .compared_metrics(prev, opts)
+                .iter()
+                .any(|m| big(m.value(self).abs_diff(m.value(prev))))
+            || self
+                .huge_page_changes(prev)
+                .iter()
+                .any(|&(_, new, old)| big(new.abs_diff(old)))
+            || self.flags != prev.flags
+            || self.vmflag_set != prev.vmflag_set
+    }
+
+    /// The metrics a change of the map shows: the chosen ones, swap and
+    /// swap_pss whenever the map was paged in or out, and lazy_free when
+    /// pages were freed with MADV_FREE or reclaimed, since that is always
+    /// worth seeing.
+    fn compared_metrics(&self, prev: &Map, opts: &Options) -> Vec<Metric> {
+        let mut metrics = opts.metrics.clone();
+        let mut add = |metric: Metric| {
+            if !metrics.contains(&metric) {
+                metrics.push(metric);
+            }
+        };
+        if self.swap != prev.swap {
+            add(Metric::Swap);
+            add(Metric::SwapPss);
+        }
+        if self.lazy_free != prev.lazy_free {
+            add(Metric::LazyFree);
+        }
+        metrics
+    }
+
+    /// Reports the changes of a map between `prev` and `self`, `secs` is
+    /// the time between the two snapshots for the rates of change and
+    /// `age` how long the map exists.
+    fn print_change(
+        &self,
+        prev: &Map,
+        secs: f64,
+        age: Duration,
+        opts: &Options,
+        events: &mut Vec<String>,
+    ) {
+        let (m, p) = (self, prev);
+        let enddiff = if m.end != p.end {
+            format!(" (was {})", p.end,)
+        } else {
+            "".to_string()
+        };
+        let compared = m.compared_metrics(p, opts);
+        let mut metricsdiff = "".to_string();
+        for metric in compared.iter() {
+            let (new, old) = (metric.value(m), metric.value(p));
+            metricsdiff.push_str(&format!(
+                " {}={}",
+                metric.name(),
+                format_kb(new, opts.human)
+            ));
+            if new != old {
+                let diff = new as i64 - old as i64;
+                let rate = if secs > 0.0 {
+                    format!(", {}", format_rate(diff as f64 / secs, opts.human))
+                } else {
+                    "".to_string()
+                };
+                metricsdiff.push_str(&format!(
+                    " (was {} diff {}{})",
+                    format_kb(old, opts.human),
+                    format_kb_diff(diff, opts.human),
+                    rate
+                ));
+            }
+        }
+        // Huge pages are always worth seeing, growing anonymous ones mean
+        // that khugepaged collapsed pages, shrinking ones a split:
+        let huge_changes = m.huge_page_changes(p);
+        for &(name, new, old) in huge_changes.iter() {
+            let what = match name {
+                "anon_huge_pages" if new > old => ", thp collapse",
+                "anon_huge_pages" => ", thp split",
+                _ => "",
+            };
+            metricsdiff.push_str(&format!(
+                " {}={} (was {} diff {}{})",
+                name,
+                format_kb(new, opts.human),
+                format_kb(old, opts.human),
+                format_kb_diff(new as i64 - old as i64, opts.human),
+                what
+            ));
+        }
+        let flagsdiff = if m.flags != p.flags {
+            format!(" flags={} (was {})", m.flags, p.flags)
+        } else {
+            "".to_string()
+        };
+        let (added, removed) = m.vmflag_changes(p);
+        let vmflagsdiff = if !added.is_empty() || !removed.is_empty() {
+            let mut changes: Vec<String> = added.iter().map(|f| format!("+{}", f)).collect();
+            changes.extend(removed.iter().map(|f| format!("-{}", f)));
+            format!(" vmflags={}", changes.join(","))
+        } else {
+            "".to_string()
+        };
+        match opts.format {
+            Format::Text => println!(
+                "{}: {:x}-{:x}{}{}{}{} age={} {}",
+                colored_tag("CHANGED", opts),
+                m.start,
+                m.end,
+                enddiff,
+                metricsdiff,
+                flagsdiff,
+                vmflagsdiff,
+                format_age(age),
+                m.labeled_name()
+            ),
+            Format::Json | Format::Ndjson => {
+                let json_list = |flags: &[&str]| -> String {
+                    let quoted: Vec<String> = flags.iter().map(|f| json_string(f)).collect();
+                    format!("[{}]", quoted.join(","))
+                };
+                let mut metrics = "".to_string();
+                for metric in compared.iter() {
+                    let diff = metric.value(m) as f64 - metric.value(p) as f64;
+                    let rate = if secs > 0.0 { diff / secs } else { 0.0 };
+                    metrics.push_str(&format!(
+                        "\"{}\":{},\"prev_{}\":{},\"{}_rate\":{:.1},",
+                        metric.name(),
+                        metric.value(m),
+                        metric.name(),
+                        metric.value(p),
+                        metric.name(),
+                        rate
+                    ));
+                }
+                for (name, new, old) in huge_changes.iter() {
+                    metrics.push_str(&format!("\"{0}\":{1},\"prev_{0}\":{2},", name, new, old));
+                }
+                events.push(format!(
+                    concat!(
+                        "{{\"event\":\"CHANGED\",\"range\":\"{:x}-{:x}\",",
+                        "\"prev_end\":\"{:x}\",{}\"flags\":{},",
+                        "\"prev_flags\":{},\"vmflags_added\":{},",
+                        "\"vmflags_removed\":{},\"age\":{:.1},\"name\":{}{}}}"
+                    ),
+                    m.start,
+                    m.end,
+                    p.end,
+                    metrics,
+                    json_string(&m.flags),
+                    json_string(&p.flags),
+                    json_list(&added),
+                    json_list(&removed),
+                    age.as_secs_f64(),
+                    json_string(&m.name),
+                    m.class_json()
+                ))
+            }
+            Format::Csv => {}
+        }
+    }
+
+    /// The huge page values of the map, by their names in the output.
+    pub fn huge_pages(&self) -> [(&'static str, u64); 5] {
+        [
+            ("anon_huge_pages", self.anon_huge_pages),
+            ("shmem_pmd_mapped", self.shmem_pmd_mapped),
+            ("file_pmd_mapped", self.file_pmd_mapped),
+            ("shared_hugetlb", self.shared_huge_tlb),
+            ("private_hugetlb", self.private_huge_tlb),
+        ]
+    }
+
+    /// The huge page values which differ from `prev`, with the new and
+    /// the old value.
+    pub fn huge_page_changes(&self, prev: &Map) -> Vec<(&'static str, u64, u64)> {
+        self.huge_pages()
+            .into_iter()
+            .zip(prev.huge_pages())
+            .filter(|((_, new), (_, old))| new != old)
+            .map(|((name, new), (_, old))| (name, new, old))
+            .collect()
+    }
+
+    pub fn key(&self) -> MapKey {
+        (self.start, self.name.clone(), self.inode)
+    }
+
+    pub fn has_vmflag(&self, flag: &str) -> bool {
+        self.vmflag_set.contains(flag)
+    }
+
+    /// Returns the VmFlags tokens which are set in `self` but not in
+    /// `prev` and those which are set in `prev` but not in `self`, in the
+    /// order of the raw VmFlags lines.
+    pub fn vmflag_changes<'a>(&'a self, prev: &'a Map) -> (Vec<&'a str>, Vec<&'a str>) {
+        let added = self
+            .vmflags
+            .split_whitespace()
+            .skip(1)
+            .filter(|f| !prev.has_vmflag(f))
+            .collect();
+        let removed = prev
+            .vmflags
+            .split_whitespace()
+            .skip(1)
+            .filter(|f| !self.has_vmflag(f))
+            .collect();
+        (added, removed)
+    }
+
+    pub fn is_readonly_mmapped_file(&self) -> bool {
+        !self.name.is_empty() && self.inode != 0 && !self.has_vmflag("wr")
+    }
+}
+
+/// One difference between two snapshots of the maps of a process, maps
+/// are identified by their start address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffEvent {
+    Mmap(Map), // a new map
+    Drop(Map), // a map which is gone
+    Changed { new: Box<Map>, prev: Box<Map> },
+    Moved { new: Box<Map>, prev: Box<Map> }, // same map at a new address
+}
+
+impl DiffEvent {
+    pub const TAGS: [&'static str; 4] = ["MMAP", "DROP", "CHANGED", "MOVED"];
+
+    /// The tag the event is shown with, one of `TAGS`.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            DiffEvent::Mmap(_) => "MMAP",
+            DiffEvent::Drop(_) => "DROP",
+            DiffEvent::Changed { .. } => "CHANGED",
+            DiffEvent::Moved { .. } => "MOVED",
+        }
+    }
+
+    /// Parses a comma separated list of event kinds like `mmap,drop` into
+    /// their tags.
+    pub fn parse_tags(s: &str) -> Result<Vec<&'static str>, String> {
+        s.split(',')
+            .map(|name| {
+                DiffEvent::TAGS
+                    .iter()
+                    .find(|tag| tag.eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| format!("Unknown event kind: {}", name))
+            })
+            .collect()
+    }
+
+    /// Prints the event for `--from-to`, with the same triple for every
+    /// compared metric, like `rss: 1000 -> 1234 (+234)`. A new map has no
+    /// previous value and a dropped one no current value, shown as `-`.
+    pub fn print_from_to(&self, opts: &Options) {
+        let (new, prev) = match self {
+            DiffEvent::Mmap(m) => (Some(m), None),
+            DiffEvent::Drop(p) => (None, Some(p)),
+            DiffEvent::Changed { new, prev } | DiffEvent::Moved { new, prev } => {
+                (Some(&**new), Some(&**prev))
+            }
+        };
+        let metrics = match (new, prev) {
+            (Some(new), Some(prev)) => new.compared_metrics(prev, opts),
+            _ => opts.metrics.clone(),
+        };
+        let value = |m: Option<&Map>, metric: Metric| match m {
+            Some(m) => format_kb(metric.value(m), opts.human),
+            None => "-".to_string(),
+        };
+        let triples: Vec<String> = metrics
+            .iter()
+            .map(|&metric| {
+                let delta = self.delta(metric);
+                format!(
+                    "{}: {} -> {} ({}{})",
+                    metric.name(),
+                    value(prev, metric),
+                    value(new, metric),
+                    // Human diffs have their sign already:
+                    if delta >= 0 && !opts.human { "+" } else { "" },
+                    format_kb_diff(delta, opts.human)
+                )
+            })
+            .collect();
+        let m = self.map();
+        let range = match self {
+            DiffEvent::Moved { new, prev } => format!(
+                "{:x}-{:x} to {:x}-{:x}",
+                prev.start, prev.end, new.start, new.end
+            ),
+            _ => format!("{:x}-{:x}", m.start, m.end),
+        };
+        println!(
+            "{}: {} {} {}",
+            colored_tag(self.tag(), opts),
+            range,
+            triples.join(" "),
+            m.labeled_name()
+        );
+    }
+
+    /// The map as it is now, or as it was for a dropped one.
+    pub fn map(&self) -> &Map {
+        match self {
+            DiffEvent::Mmap(m) | DiffEvent::Drop(m) => m,
+            DiffEvent::Changed { new, .. } | DiffEvent::Moved { new, .. } => new,
+        }
+    }
+
+    /// The signed change of `metric`, a new map counts fully positive and
+    /// a dropped one fully negative.
+    pub fn delta(&self, metric: Metric) -> i64 {
+        match self {
+            DiffEvent::Mmap(m) => metric.value(m) as i64,
+            DiffEvent::Drop(m) => -(metric.value(m) as i64),
+            DiffEvent::Changed { new, prev } | DiffEvent::Moved { new, prev } => {
+                metric.value(new) as i64 - metric.value(prev) as i64
+            }
+        }
+    }
+}
+
+/// The counts of the events between two snapshots and the net changes
+/// of the totals, for logging them as a time series.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub dropped: usize,
+    pub changed: usize,
+    pub moved: usize,
+    pub rss_delta: i64, // in kB, like the deltas below
+    pub pss_delta: i64,
+    pub swap_delta: i64,
+}
+
+impl DiffSummary {
+    /// Counts the events of a diff, without any filters, and sums up
+    /// their rss, pss and swap changes.
+    pub fn of(events: &[DiffEvent]) -> DiffSummary {
+        let mut summary = DiffSummary::default();
+        for event in events {
+            match event {
+                DiffEvent::Mmap(_) => summary.added += 1,
+                DiffEvent::Drop(_) => summary.dropped += 1,
+                DiffEvent::Changed { .. } => summary.changed += 1,
+                DiffEvent::Moved { .. } => summary.moved += 1,
+            }
+            summary.rss_delta += event.delta(Metric::Rss);
+            summary.pss_delta += event.delta(Metric::Pss);
+            summary.swap_delta += event.delta(Metric::Swap);
+        }
+        summary
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"added\":{},\"dropped\":{},\"changed\":{},\"moved\":{},",
+                "\"rss_delta\":{},\"pss_delta\":{},\"swap_delta\":{}}}"
+            ),
+            self.added,
+            self.dropped,
+            self.changed,
+            self.moved,
+            self.rss_delta,
+            self.pss_delta,
+            self.swap_delta
+        )
+    }
+
+    /// Prints the summary as the footer of a text diff.
+    pub fn print(&self, human: bool) {
+        println!(
+            "Summary: {} added, {} dropped, {} changed, {} moved, rss {}, pss {}, swap {}",
+            self.added,
+            self.dropped,
+            self.changed,
+            self.moved,
+            format_kb_diff(self.rss_delta, human),
+            format_kb_diff(self.pss_delta, human),
+            format_kb_diff(self.swap_delta, human)
+        );
+    }
+}
+
+/// What identifies a map across snapshots for its age: its start
+/// address, name and inode. A map which moved or was mapped again from
+/// another file is a new one.
+pub type MapKey = (u64, String, u64);
+
+#[derive(Clone)]
+pub struct Maps {
+    pub pid: i32,
+    pub time: SystemTime,
+    pub maps: Vec<Map>,
+    pub first_seen: HashMap<MapKey, SystemTime>, // see `carry_ages`
+}
+
+impl Maps {
+    pub fn get_maps(pid: i32) -> Result<Maps, MapError> {
+        Maps::get_maps_from(pid, Source::Smaps)
+    }
+
+    /// Reads the maps of `pid` from its smaps or, much more cheaply, its
+    /// maps file. From the latter only the ranges, flags, names and the
+    /// sizes derived from the ranges are known, all stats are 0.
+    pub fn get_maps_from(pid: i32, source: Source) -> Result<Maps, MapError> {
+        let path = format!("/proc/{}/{}", pid, source.file_name());
+        let contents =
+            fs::read_to_string(&path).map_err(|e| MapError::of_process(pid, &path, e))?;
+        match source {
+            Source::Smaps => Maps::from_str(&contents, pid),
+            Source::Maps => Maps::from_maps_str(&contents, pid),
+        }
+    }
+
+    /// Reads the maps of the calling process from /proc/self/smaps, for
+    /// checking the own mapping churn of a program.
+    pub fn get_own_maps() -> Result<Maps, MapError> {
+        Maps::from_file("/proc/self/smaps", std::process::id() as i32)
+    }
+
+    /// Reads a captured smaps dump (or any smaps file) from `path`.
+    pub fn from_file(path: &str, pid: i32) -> Result<Maps, MapError> {
+        let contents = fs::read_to_string(path).map_err(|e| MapError::io(path, e))?;
+        Maps::from_str(&contents, pid)
+    }
+
+    /// Parses the contents of an smaps file, `pid` is only recorded. The
+    /// maps are sorted by start address, which `diff` relies on, even if
+    /// the file is not.
+    pub fn from_str(contents: &str, pid: i32) -> Result<Maps, MapError> {
+        let mut lines = contents.lines();
+        let mut res = Maps {
+            pid,
+            time: SystemTime::now(),
+            maps: vec![],
+            first_seen: HashMap::new(),
+        };
+        loop {
+            match Map::parse_from_line_iterator(&mut lines)? {
+                None => {
+                    res.maps.sort_by_key(|m| m.start);
+                    return Ok(res);
+                }
+                Some(m) => res.maps.push(m),
+            }
+        }
+    }
+
+    /// Parses the contents of a /proc/PID/maps file, one map per line.
+    pub fn from_maps_str(contents: &str, pid: i32) -> Result<Maps, MapError> {
+        let mut res = Maps {
+            pid,
+            time: SystemTime::now(),
+            maps: vec![],
+            first_seen: HashMap::new(),
+        };
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            let mut map = Map::parse_first_line(line)?;
+            map.size = map.end.saturating_sub(map.start) / 1024;
+            res.maps.push(map);
+        }
+        res.maps.sort_by_key(|m| m.start);
+        Ok(res)
+    }
+
+    /// Appends this snapshot to the recording at `path`. Each snapshot is
+    /// a header line with pid and time, the maps in compact smaps format
+    /// and an empty line.
+    pub fn record(&self, path: &str) -> Result<(), MapError> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| MapError::io(path, e))?;
+        file.write_all(self.to_record().as_bytes())
+            .map_err(|e| MapError::io(path, e))
+    }
+
+    /// The snapshot as `record` appends it to a recording.
+    pub fn to_record(&self) -> String {
+        let d = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut out = format!(
+            "{} pid={} time={}.{:09}\n",
+            RECORD_HEADER,
+            self.pid,
+            d.as_secs(),
+            d.subsec_nanos()
+        );
+        for m in self.maps.iter() {
+            out.push_str(&m.to_smaps());
+        }
+        out.push('\n');
+        out
+    }
+
+    /// Opens a recording made with `record` to read its snapshots one at
+    /// a time, in order. A gzip compressed recording is decompressed with
+    /// the `gzip` tool, which must be installed.
+    pub fn read_recording(path: &str) -> Result<Recording, MapError> {
+        Recording::open(path)
+    }
+
+    pub fn pretty_print(&self, opts: &Options) {
+        if let Some(n) = opts.watch_top {
+            return self.print_top(n, opts);
+        }
+        if let Some(by) = opts.group_by {
+            return self.print_groups(by, opts);
+        }
+        match opts.format {
+            Format::Text => {
+                let maps = opts.select(&self.maps);
+                if !opts.layout.is_compact(maps.len()) {
+                    for m in maps {
+                        m.pretty_print(opts.human);
+                    }
+                    return;
+                }
+                println!(
+                    "{:<25} {:<5} {:>12} {:>6} {:>12} {:>6} {:>12} {:>8}  NAME",
+                    "RANGE", "PERMS", "RSS", "RSS%", "PSS", "PSS%", "SWAP", "AGE"
+                );
+                let (rss, pss) = self.rss_pss_totals();
+                for m in maps {
+                    println!(
+                        "{:<25} {:<5} {:>12} {:>5.1}% {:>12} {:>5.1}% {:>12} {:>8}  {}",
+                        format!("{:x}-{:x}", m.start, m.end),
+                        m.flags,
+                        format_kb(m.rss, opts.human),
+                        percent(m.rss, rss),
+                        format_kb(m.pss, opts.human),
+                        percent(m.pss, pss),
+                        format_kb(m.swap, opts.human),
+                        format_age(self.age(m)),
+                        m.name
+                    );
+                }
+                println!();
+            }
+            Format::Json => println!("{}", self.to_json(opts)),
+            Format::Ndjson => {
+                let time = opts.time_format.format(self.time);
+                let totals = self.rss_pss_totals();
+                for m in opts.select(&self.maps) {
+                    println!(
+                        "{{\"pid\":{},\"time\":\"{}\",{}",
+                        self.pid,
+                        time,
+                        &Maps::map_json(m, totals)[1..]
+                    );
+                }
+            }
+            Format::Csv => {
+                let timestamp = opts.time_format.format(self.time);
+                for m in opts.select(&self.maps) {
+                    println!("{}", m.to_csv(&timestamp, self.pid));
+                }
+            }
+        }
+    }
+
+    /// Prints the `n` maps with the largest pss below a header with the
+    /// totals, for `--watch-top`.
+    pub fn print_top(&self, n: usize, opts: &Options) {
+        let mut top: Vec<&Map> = self.maps.iter().filter(|m| opts.shows(m)).collect();
+        top.sort_by_key(|m| std::cmp::Reverse(m.pss));
+        top.truncate(n);
+        let (rss, pss, swap) = (
+            self.total(Metric::Rss),
+            self.total(Metric::Pss),
+            self.total(Metric::Swap),
+        );
+        match opts.format {
+            Format::Text => {
+                println!(
+                    "pid {} at {}: maps={} rss={} pss={} swap={}\n",
+                    self.pid,
+                    opts.time_format.format(self.time),
+                    self.maps.len(),
+                    format_total(rss, opts.human),
+                    format_total(pss, opts.human),
+                    format_total(swap, opts.human)
+                );
+                println!(
+                    "{:>12} {:>12} {:>12} {:>12}  {:<25} NAME",
+                    "PSS", "RSS", "SIZE", "SWAP", "RANGE"
+                );
+                for m in top {
+                    println!(
+                        "{:>12} {:>12} {:>12} {:>12}  {:<25} {}",
+                        format_kb(m.pss, opts.human),
+                        format_kb(m.rss, opts.human),
+                        format_kb(m.size, opts.human),
+                        format_kb(m.swap, opts.human),
+                        format!("{:x}-{:x}", m.start, m.end),
+                        m.name
+                    );
+                }
+                println!();
+            }
+            Format::Json | Format::Ndjson => {
+                let maps: Vec<String> = top
+                    .into_iter()
+                    .map(|m| Maps::map_json(m, (rss, pss)))
+                    .collect();
+                println!(
+                    concat!(
+                        "{{\"pid\":{},\"time\":\"{}\",\"totals\":{{\"maps\":{},\"rss\":{},",
+                        "\"pss\":{},\"swap\":{}}},\"top\":[{}]}}"
+                    ),
+                    self.pid,
+                    opts.time_format.format(self.time),
+                    self.maps.len(),
+                    rss,
+                    pss,
+                    swap,
+                    maps.join(",")
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+
+    /// The total rss and pss of the snapshot, which the shares of the
+    /// maps in percent are computed against.
+    fn rss_pss_totals(&self) -> (u64, u64) {
+        (self.total(Metric::Rss), self.total(Metric::Pss))
+    }
+
+    /// A map as JSON together with its share of the rss and pss totals.
+    fn map_json(m: &Map, (rss, pss): (u64, u64)) -> String {
+        let json = m.to_json();
+        format!(
+            "{},\"rss_percent\":{:.2},\"pss_percent\":{:.2}}}",
+            &json[..json.len() - 1],
+            percent(m.rss, rss),
+            percent(m.pss, pss)
+        )
+    }
+
+    pub fn to_json(&self, opts: &Options) -> String {
+        let totals = self.rss_pss_totals();
+        let maps: Vec<String> = opts
+            .select(&self.maps)
+            .into_iter()
+            .map(|m| Maps::map_json(m, totals))
+            .collect();
+        format!(
+            "{{\"pid\":{},\"time\":\"{}\",\"maps\":[{}]}}",
+            self.pid,
+            opts.time_format.format(self.time),
+            maps.join(",")
+        )
+    }
+
+    /// Computes all differences between `prev` and this snapshot. Unlike
+    /// `print_diff` this does not apply any filters and reports a map as
+    /// changed if any of its values differs. A dropped and a new map with
+    /// the same name, permissions and a similar size are reported as moved.
+    pub fn diff(&self, prev: &Maps) -> Vec<DiffEvent> {
+        // Both are sorted by start address when parsed, the merge below
+        // would report garbage otherwise:
+        debug_assert!(self.is_sorted(), "maps of pid {} not sorted", self.pid);
+        debug_assert!(prev.is_sorted(), "maps of pid {} not sorted", prev.pid);
+        let mut events: Vec<DiffEvent> = vec![];
+        let mut i: usize = 0; // position in self.maps
+        let mut j: usize = 0; // position in prev.maps
+        while i < self.maps.len() && j < prev.maps.len() {
+            let m = &self.maps[i];
+            let p = &prev.maps[j];
+            if m.start < p.start {
+                events.push(DiffEvent::Mmap(m.clone()));
+                i += 1;
+            } else if m.start > p.start {
+                events.push(DiffEvent::Drop(p.clone()));
+                j += 1;
+            } else {
+                if m != p {
+                    events.push(DiffEvent::Changed {
+                        new: Box::new(m.clone()),
+                        prev: Box::new(p.clone()),
+                    });
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+        events.extend(self.maps[i..].iter().cloned().map(DiffEvent::Mmap));
+        events.extend(prev.maps[j..].iter().cloned().map(DiffEvent::Drop));
+        // Pair up dropped maps with new ones which look like they were
+        // just moved, each new map is used at most once:
+        let mut slots: Vec<Option<DiffEvent>> = events.into_iter().map(Some).collect();
+        for d in 0..slots.len() {
+            let p = match &slots[d] {
+                Some(DiffEvent::Drop(p)) => p.clone(),
+                _ => continue,
+            };
+            let found = slots
+                .iter()
+                .position(|e| matches!(e, Some(DiffEvent::Mmap(m)) if m.looks_moved_from(&p)));
+            if let Some(n) = found {
+                if let Some(DiffEvent::Mmap(m)) = slots[n].take() {
+                    slots[d] = None;
+                    slots[n] = Some(DiffEvent::Moved {
+                        new: Box::new(m),
+                        prev: Box::new(p),
+                    });
+                }
+            }
+        }
+        slots.into_iter().flatten().collect()
+    }
+
+    /// Counts the events of `diff` to `prev`, without any filters, and
+    /// sums up their rss, pss and swap changes.
+    pub fn diff_summary(&self, prev: &Maps) -> DiffSummary {
+        DiffSummary::of(&self.diff(prev))
+    }
+
+    /// Takes over when the maps were first seen from the `prev` snapshot,
+    /// maps which are new since then are first seen now. The maps of a
+    /// first snapshot count as first seen at its time.
+    pub fn carry_ages(&mut self, prev: &Maps) {
+        let prev_seen: HashMap<MapKey, SystemTime> = prev
+            .maps
+            .iter()
+            .map(|p| (p.key(), prev.first_seen(p)))
+            .collect();
+        self.first_seen = self
+            .maps
+            .iter()
+            .map(|m| {
+                let key = m.key();
+                let seen = prev_seen.get(&key).copied().unwrap_or(self.time);
+                (key, seen)
+            })
+            .collect();
+    }
+
+    /// When the map was first seen, see `carry_ages`.
+    pub fn first_seen(&self, m: &Map) -> SystemTime {
+        self.first_seen.get(&m.key()).copied().unwrap_or(self.time)
+    }
+
+    /// How long the map exists at the time of this snapshot, as far as
+    /// we watched it.
+    pub fn age(&self, m: &Map) -> Duration {
+        self.time
+            .duration_since(self.first_seen(m))
+            .unwrap_or_default()
+    }
+
+    /// Tells if the maps are sorted by start address.
+    pub fn is_sorted(&self) -> bool {
+        self.maps.windows(2).all(|w| w[0].start <= w[1].start)
+    }
+
+    /// Returns the total size, and size and rss of read only mmapped files.
+    fn size_totals(&self) -> (u64, u64, u64) {
+        let mmapped = self.maps.iter().filter(|m| m.is_readonly_mmapped_file());
+        let mmapped_rss: u64 = mmapped.clone().map(|m| m.rss).sum();
+        (
+            self.total(Metric::Size),
+            mmapped.map(|m| m.size).sum(),
+            mmapped_rss,
+        )
+    }
+
+    pub fn total(&self, metric: Metric) -> u64 {
+        self.maps.iter().map(|m| metric.value(m)).sum()
+    }
+
+    /// The rss of the anonymous maps and that of the file backed ones.
+    /// Anonymous memory is what leaks, file pages can be dropped and read
+    /// again.
+    pub fn rss_split(&self) -> (u64, u64) {
+        let (anon, file): (Vec<&Map>, Vec<&Map>) = self.maps.iter().partition(|m| m.is_anonymous());
+        (
+            anon.iter().map(|m| m.rss).sum(),
+            file.iter().map(|m| m.rss).sum(),
+        )
+    }
+
+    /// The largest free range of addresses between two maps in kB, from
+    /// the maps sorted by address. A largest gap which shrinks while the
+    /// number of maps grows hints at fragmentation. The gaps which are
+    /// part of the randomized layout are left out: the one up to the
+    /// `[vsyscall]` page at the very top, the one below the stack, and
+    /// the one of terabytes between the heap (or the program, without a
+    /// heap) and the maps the kernel places downwards from there.
+    pub fn largest_gap(&self) -> u64 {
+        let maps: Vec<&Map> = self
+            .maps
+            .iter()
+            .filter(|m| !m.name.starts_with("[vsyscall]"))
+            .collect();
+        let program = maps.first().map(|m| &m.name[..]).unwrap_or_default();
+        let layout_hole = maps
+            .iter()
+            .rposition(|m| m.name == "[heap]")
+            .or_else(|| maps.iter().rposition(|m| m.name == program));
+        let largest_gap = maps
+            .windows(2)
+            .enumerate()
+            .filter(|(i, pair)| Some(*i) != layout_hole && pair[1].name != "[stack]")
+            .map(|(_, pair)| pair[1].start.saturating_sub(pair[0].end))
+            .max()
+            .unwrap_or(0);
+        largest_gap / 1024
+    }
+
+    /// Returns the map which contains `address`, if any.
+    pub fn containing(&self, address: u64) -> Option<&Map> {
+        self.maps
+            .iter()
+            .find(|m| m.start <= address && address < m.end)
+    }
+
+    /// Prints one line with the sums over all maps, regardless of filters.
+    pub fn print_totals(&self, opts: &Options) {
+        let sum = |f: fn(&Map) -> u64| -> u64 { self.maps.iter().map(f).sum() };
+        let (anon_rss, file_rss) = self.rss_split();
+        let totals = [
+            ("size", sum(|m| m.size)),
+            ("rss", sum(|m| m.rss)),
+            ("anon_rss", anon_rss),
+            ("file_rss", file_rss),
+            ("pss", sum(|m| m.pss)),
+            ("swap", sum(|m| m.swap)),
+            ("shared", sum(Map::total_shared)),
+            ("private", sum(Map::total_private)),
+            ("dirty", sum(Map::total_dirty)),
+            ("private_dirty", sum(|m| m.private_dirty)),
+            ("anonymous", sum(|m| m.anonymous)),
+            ("ksm", sum(|m| m.ksm)),
+            ("lazy_free", sum(|m| m.lazy_free)),
+            ("largest_gap", self.largest_gap()),
+        ];
+        match opts.format {
+            Format::Text => {
+                let values: Vec<String> = totals
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, format_total(*value, opts.human)))
+                    .collect();
+                println!(
+                    "Totals for pid {}: maps={} {}",
+                    self.pid,
+                    self.maps.len(),
+                    values.join(" ")
+                );
+                if opts.hugepage_summary {
+                    println!("{}", self.huge_page_line(None, opts));
+                }
+                if let Some(note) = self.lazy_free_note(opts) {
+                    println!("{}", note);
+                }
+            }
+            Format::Json | Format::Ndjson => {
+                let values: Vec<String> = totals
+                    .iter()
+                    .map(|(name, value)| format!("\"{}\":{}", name, value))
+                    .collect();
+                let huge_pages = if opts.hugepage_summary {
+                    self.huge_page_json(None)
+                } else {
+                    "".to_string()
+                };
+                println!(
+                    "{{\"pid\":{},\"time\":\"{}\",\"totals\":{{\"maps\":{},{}{}}}}}",
+                    self.pid,
+                    opts.time_format.format(self.time),
+                    self.maps.len(),
+                    values.join(","),
+                    huge_pages
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+
+    /// The huge page values summed up over all maps.
+    pub fn huge_page_totals(&self) -> [(&'static str, u64); 5] {
+        let mut totals = Map::default().huge_pages();
+        for m in self.maps.iter() {
+            for (total, (_, value)) in totals.iter_mut().zip(m.huge_pages()) {
+                total.1 += value;
+            }
+        }
+        totals
+    }
+
+    /// The line of `--hugepage-summary` with the huge page totals, and
+    /// their changes since `prev` if given.
+    pub fn huge_page_line(&self, prev: Option<&Maps>, opts: &Options) -> String {
+        let prev_totals = prev.map(Maps::huge_page_totals);
+        let values: Vec<String> = self
+            .huge_page_totals()
+            .iter()
+            .enumerate()
+            .map(|(i, &(name, value))| {
+                let change = match prev_totals {
+                    Some(old) if old[i].1 != value => format!(
+                        " (was {} diff {})",
+                        format_total(old[i].1, opts.human),
+                        format_kb_diff(value as i64 - old[i].1 as i64, opts.human)
+                    ),
+                    _ => "".to_string(),
+                };
+                format!("{}={}{}", name, format_total(value, opts.human), change)
+            })
+            .collect();
+        format!("Huge pages for pid {}: {}", self.pid, values.join(" "))
+    }
+
+    /// The huge page totals as JSON fields with a leading comma, and the
+    /// previous values if `prev` is given.
+    fn huge_page_json(&self, prev: Option<&Maps>) -> String {
+        let prev_totals = prev.map(Maps::huge_page_totals);
+        self.huge_page_totals()
+            .iter()
+            .enumerate()
+            .map(|(i, (name, value))| match prev_totals {
+                Some(old) => format!(",\"{0}\":{1},\"prev_{0}\":{2}", name, value, old[i].1),
+                None => format!(",\"{}\":{}", name, value),
+            })
+            .collect()
+    }
+
+    /// A note for text output if a large part of the rss is lazy_free,
+    /// since the allocator can hand these pages back at any time and the
+    /// memory really used is lower than the rss suggests.
+    pub fn lazy_free_note(&self, opts: &Options) -> Option<String> {
+        let (rss, lazy_free) = (self.total(Metric::Rss), self.total(Metric::LazyFree));
+        let share = percent(lazy_free, rss);
+        (share >= LAZY_FREE_NOTE_PERCENT).then(|| {
+            format!(
+                "note: {} ({:.1}%) of rss is lazy_free (MADV_FREE) and can be reclaimed",
+                format_total(lazy_free, opts.human),
+                share
+            )
+        })
+    }
+
+    /// Prints one line with the total rss, pss, swap and number of maps and
+    /// their changes since `prev`, instead of a full diff.
+    pub fn print_total_changes(&self, prev: &Maps, opts: &Options) {
+        let totals = [
+            ("rss", self.total(Metric::Rss), prev.total(Metric::Rss)),
+            ("pss", self.total(Metric::Pss), prev.total(Metric::Pss)),
+            ("swap", self.total(Metric::Swap), prev.total(Metric::Swap)),
+            ("maps", self.maps.len() as u64, prev.maps.len() as u64),
+        ];
+        match opts.format {
+            Format::Text => {
+                let values: Vec<String> = totals
+                    .iter()
+                    .map(|&(name, value, old)| {
+                        // The number of maps is a count, not a size:
+                        let human = opts.human && name != "maps";
+                        format!(
+                            "{}={} ({})",
+                            name,
+                            format_total(value, human),
+                            format_kb_diff(value as i64 - old as i64, human)
+                        )
+                    })
+                    .collect();
+                println!(
+                    "pid {} at {}: {}",
+                    self.pid,
+                    opts.time_format.format(self.time),
+                    values.join(" ")
+                );
+            }
+            Format::Json | Format::Ndjson => {
+                let values: Vec<String> = totals
+                    .iter()
+                    .map(|(name, value, old)| {
+                        format!("\"{0}\":{1},\"prev_{0}\":{2}", name, value, old)
+                    })
+                    .collect();
+                println!(
+                    "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\",\"totals\":{{{}}}}}",
+                    self.pid,
+                    opts.time_format.format(prev.time),
+                    opts.time_format.format(self.time),
+                    values.join(",")
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+
+    /// Prints one line with the total rss and how much it changed since
+    /// the `first` snapshot, like `total rss: 1.2 GiB (↑ +340.0 MiB since
+    /// start)`, to see at a glance if a process still grows.
+    pub fn print_trend(&self, first: &Maps, opts: &Options) {
+        let (rss, rss_first) = (self.total(Metric::Rss), first.total(Metric::Rss));
+        let arrow = match rss.cmp(&rss_first) {
+            std::cmp::Ordering::Greater => "↑",
+            std::cmp::Ordering::Less => "↓",
+            std::cmp::Ordering::Equal => "→",
+        };
+        println!(
+            "total rss: {} ({} {} since start)",
+            format_total(rss, opts.human),
+            arrow,
+            format_kb_diff(rss as i64 - rss_first as i64, opts.human)
+        );
+    }
+
+    /// Prints the net change between the `first` snapshot of a run and
+    /// this one, counting maps by their start address. `samples` is the
+    /// number of snapshots taken from the first to this one.
+    pub fn print_summary(&self, first: &Maps, samples: u64, opts: &Options) {
+        let new_maps = self
+            .maps
+            .iter()
+            .filter(|m| !first.maps.iter().any(|f| f.start == m.start))
+            .count();
+        let dropped_maps = first
+            .maps
+            .iter()
+            .filter(|f| !self.maps.iter().any(|m| m.start == f.start))
+            .count();
+        let elapsed = self.time.duration_since(first.time).unwrap_or_default();
+        let (size, size_first) = (self.total(Metric::Size), first.total(Metric::Size));
+        let (rss, rss_first) = (self.total(Metric::Rss), first.total(Metric::Rss));
+        let (pss, pss_first) = (self.total(Metric::Pss), first.total(Metric::Pss));
+        let (gap, gap_first) = (self.largest_gap(), first.largest_gap());
+        let (maps, maps_first) = (self.maps.len(), first.maps.len());
+        match opts.format {
+            Format::Text => {
+                println!(
+                    "\nSummary for pid {} since {}, {} samples in {}:",
+                    self.pid,
+                    opts.time_format.format(first.time),
+                    samples,
+                    format_duration(elapsed)
+                );
+                println!(
+                    "  size={} (was {} diff {}) rss={} (was {} diff {}) pss={} (was {} diff {}) maps={} (was {} diff {:+}) largest_gap={} (was {} diff {}), {} new maps, {} dropped maps",
+                    format_total(size, opts.human),
+                    format_total(size_first, opts.human),
+                    format_kb_diff(size as i64 - size_first as i64, opts.human),
+                    format_total(rss, opts.human),
+                    format_total(rss_first, opts.human),
+                    format_kb_diff(rss as i64 - rss_first as i64, opts.human),
+                    format_total(pss, opts.human),
+                    format_total(pss_first, opts.human),
+                    format_kb_diff(pss as i64 - pss_first as i64, opts.human),
+                    maps,
+                    maps_first,
+                    maps as i64 - maps_first as i64,
+                    format_total(gap, opts.human),
+                    format_total(gap_first, opts.human),
+                    format_kb_diff(gap as i64 - gap_first as i64, opts.human),
+                    new_maps,
+                    dropped_maps
+                );
+            }
+            Format::Json | Format::Ndjson => println!(
+                concat!(
+                    "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\",\"summary\":{{",
+                    "\"elapsed\":{:.3},\"samples\":{},\"size\":{},\"prev_size\":{},",
+                    "\"rss\":{},\"prev_rss\":{},\"pss\":{},\"prev_pss\":{},",
+                    "\"maps\":{},\"prev_maps\":{},",
+                    "\"largest_gap\":{},\"prev_largest_gap\":{},\"new_maps\":{},",
+                    "\"dropped_maps\":{}}}}}"
+                ),
+                self.pid,
+                opts.time_format.format(first.time),
+                opts.time_format.format(self.time),
+                elapsed.as_secs_f64(),
+                samples,
+                size,
+                size_first,
+                rss,
+                rss_first,
+                pss,
+                pss_first,
+                maps,
+                maps_first,
+                gap,
+                gap_first,
+                new_maps,
+                dropped_maps
+            ),
+            Format::Csv => {}
+        }
+    }
+
+    pub fn groups(&self, by: GroupBy, opts: &Options) -> HashMap<String, GroupStats> {
+        group_maps(self.maps.iter().filter(|m| opts.shows(m)), by)
+    }
+
+    pub fn print_groups(&self, by: GroupBy, opts: &Options) {
+        let groups = self.groups(by, opts);
+        let keys = sorted_keys(&groups, &HashMap::new());
+        match opts.format {
+            Format::Text => {
+                for key in keys.iter() {
+                    let g = &groups[key];
+                    let values: Vec<String> = Metric::ALL
+                        .iter()
+                        .map(|metric| {
+                            format!(
+                                "{}={}",
+                                metric.name(),
+                                format_kb(g.value(*metric), opts.human)
+                            )
+                        })
+                        .collect();
+                    println!("{}: maps={} {}", key, g.count, values.join(" "));
+                }
+            }
+            Format::Json | Format::Ndjson => {
+                let groups: Vec<String> = keys.iter().map(|k| groups[k].to_json(k)).collect();
+                println!(
+                    "{{\"pid\":{},\"time\":\"{}\",\"groups\":[{}]}}",
+                    self.pid,
+                    opts.time_format.format(self.time),
+                    groups.join(",")
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+
+    /// Like `print_diff`, but compares the groups of both snapshots.
+    pub fn print_group_diff(&self, prev: &Maps, by: GroupBy, opts: &Options) {
+        let new_groups = self.groups(by, opts);
+        let old_groups = prev.groups(by, opts);
+        if opts.format == Format::Text {
+            println!(
+                "\nDifferences in map groups of pid {} between {} and {}:",
+                self.pid,
+                opts.time_format.format(prev.time),
+                opts.time_format.format(self.time),
+            );
+        }
+        let mut events: Vec<String> = vec![];
+        for key in sorted_keys(&new_groups, &old_groups) {
+            let (kind, new, old) = match (new_groups.get(&key), old_groups.get(&key)) {
+                (Some(new), None) => ("NEW", new.clone(), GroupStats::default()),
+                (None, Some(old)) => ("GONE", GroupStats::default(), old.clone()),
+                (Some(new), Some(old)) if new != old => ("CHANGED", new.clone(), old.clone()),
+                _ => continue,
+            };
+            match opts.format {
+                Format::Text => {
+                    let mut line =
+                        format!("{}: {} maps={}", colored_tag(kind, opts), key, new.count);
+                    if new.count != old.count {
+                        line.push_str(&format!(" (was {})", old.count));
+                    }
+                    for metric in opts.metrics.iter() {
+                        let (n, o) = (new.value(*metric), old.value(*metric));
+                        line.push_str(&format!(" {}={}", metric.name(), format_kb(n, opts.human)));
+                        if n != o {
+                            line.push_str(&format!(
+                                " (was {} diff {})",
+                                format_kb(o, opts.human),
+                                format_kb_diff(n as i64 - o as i64, opts.human)
+                            ));
+                        }
+                    }
+                    println!("{}", line);
+                }
+                Format::Json | Format::Ndjson => events.push(format!(
+                    "{{\"event\":\"{}\",\"new\":{},\"prev\":{}}}",
+                    kind,
+                    new.to_json(&key),
+                    old.to_json(&key)
+                )),
+                Format::Csv => {}
+            }
+        }
+        match opts.format {
+            Format::Json => println!(
+                "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\",\"events\":[{}]}}",
+                self.pid,
+                opts.time_format.format(prev.time),
+                opts.time_format.format(self.time),
+                events.join(",")
+            ),
+            Format::Ndjson => self.print_event_lines(prev, &events, opts),
+            _ => {}
+        }
+    }
+
+    /// Prints the collected JSON events of a diff one per line, each with
+    /// the pid and the times of the two snapshots.
+    fn print_event_lines(&self, prev: &Maps, events: &[String], opts: &Options) {
+        let (from, to) = (
+            opts.time_format.format(prev.time),
+            opts.time_format.format(self.time),
+        );
+        for event in events {
+            println!(
+                "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\",{}",
+                self.pid,
+                from,
+                to,
+                &event[1..]
+            );
+        }
+    }
+
+    pub fn print_diff(&self, prev: &Maps, opts: &Options) {
+        self.print_events(prev, &self.diff(prev), opts, || {});
+    }
+
+    /// Prints the diff to `prev` like `print_diff`, with its `events`
+    /// computed by the caller, who needed them anyway. `header` is called
+    /// right before anything is printed. Returns if anything was, a text
+    /// diff without events is left out entirely.
+    pub fn print_events(
+        &self,
+        prev: &Maps,
+        events: &[DiffEvent],
+        opts: &Options,
+        header: impl FnOnce(),
+    ) -> bool {
+        assert_eq!(self.pid, prev.pid);
+        let format = opts.format;
+        let mut changes = opts.shown_events(events);
+        // Nothing to see, not even a header, for a quiet process:
+        if format == Format::Text
+            && changes.is_empty()
+            && opts.watch_top.is_none()
+            && opts.group_by.is_none()
+        {
+            return false;
+        }
+        header();
+        if let Some(n) = opts.watch_top {
+            self.print_top(n, opts);
+            return true;
+        }
+        if let Some(by) = opts.group_by {
+            self.print_group_diff(prev, by, opts);
+            return true;
+        }
+        if format == Format::Csv {
+            // CSV has no diffs, but the full new sample:
+            self.pretty_print(opts);
+            return true;
+        }
+        let summary = DiffSummary::of(events);
+        // Only the largest changes are shown of a very busy diff, by the
+        // sort metric or by rss:
+        let more = match opts.max_events {
+            Some(n) if changes.len() > n => {
+                let metric = opts.sort_by.unwrap_or(Metric::Rss);
+                changes.sort_by_key(|event| std::cmp::Reverse(event.delta(metric).unsigned_abs()));
+                let more = changes.len() - n;
+                changes.truncate(n);
+                more
+            }
+            _ => 0,
+        };
+        let more_json = if more > 0 {
+            format!(",\"more_events\":{}", more)
+        } else {
+            "".to_string()
+        };
+        if format == Format::Text {
+            println!(
+                "\nDifferences in maps of pid {} between {} and {}:",
+                self.pid,
+                opts.time_format.format(prev.time),
+                opts.time_format.format(self.time),
+            );
+            if let Some(interval) = opts.interval(prev.time, self.time) {
+                println!("{}", interval);
+            }
+        }
+        // The measured time between the samples, not the configured delay:
+        let secs = self
+            .time
+            .duration_since(prev.time)
+            .unwrap_or_default()
+            .as_secs_f64();
+        // In JSON mode the events are collected and printed as one object:
+        let mut events: Vec<String> = vec![];
+        for event in changes.iter() {
+            if opts.from_to && format == Format::Text {
+                event.print_from_to(opts);
+                continue;
+            }
+            match event {
+                DiffEvent::Mmap(m) => m.print_event("MMAP", opts, &mut events),
+                DiffEvent::Drop(p) => p.print_event("DROP", opts, &mut events),
+                DiffEvent::Changed { new, prev } => {
+                    new.print_change(prev, secs, self.age(new), opts, &mut events)
+                }
+                DiffEvent::Moved { new, prev } => new.print_move(prev, opts, &mut events),
+            }
+        }
+        if more > 0 && format == Format::Text {
+            println!("(... and {} more)", more);
+        }
+        let (total_size, total_mmapped, total_mmapped_rss) = self.size_totals();
+        let (total_size_prev, total_mmapped_prev, total_mmapped_rss_prev) = prev.size_totals();
+        let (maps, maps_prev) = (self.maps.len(), prev.maps.len());
+        let (swap, swap_prev) = (self.total(Metric::Swap), prev.total(Metric::Swap));
+        let (swap_pss, swap_pss_prev) = (self.total(Metric::SwapPss), prev.total(Metric::SwapPss));
+        let (lazy_free, lazy_free_prev) =
+            (self.total(Metric::LazyFree), prev.total(Metric::LazyFree));
+        let ((anon_rss, file_rss), (anon_rss_prev, file_rss_prev)) =
+            (self.rss_split(), prev.rss_split());
+        let (gap, gap_prev) = (self.largest_gap(), prev.largest_gap());
+        let totals = format!(
+            concat!(
+                "{{\"size\":{},\"prev_size\":{},\"mmapped\":{},\"prev_mmapped\":{},",
+                "\"mmapped_rss\":{},\"prev_mmapped_rss\":{},\"maps\":{},\"prev_maps\":{},",
+                "\"anon_rss\":{},\"prev_anon_rss\":{},\"file_rss\":{},\"prev_file_rss\":{},",
+                "\"swap\":{},\"prev_swap\":{},\"swap_pss\":{},\"prev_swap_pss\":{},",
+                "\"lazy_free\":{},\"prev_lazy_free\":{},",
+                "\"largest_gap\":{},\"prev_largest_gap\":{}{}}}"
+            ),
+            total_size,
+            total_size_prev,
+            total_mmapped,
+            total_mmapped_prev,
+            total_mmapped_rss,
+            total_mmapped_rss_prev,
+            maps,
+            maps_prev,
+            anon_rss,
+            anon_rss_prev,
+            file_rss,
+            file_rss_prev,
+            swap,
+            swap_prev,
+            swap_pss,
+            swap_pss_prev,
+            lazy_free,
+            lazy_free_prev,
+            gap,
+            gap_prev,
+            if opts.hugepage_summary {
+                self.huge_page_json(Some(prev))
+            } else {
+                "".to_string()
+            } + &if opts.working_set {
+                format!(",\"working_set\":{}", self.total(Metric::Referenced))
+            } else {
+                "".to_string()
+            }
+        );
+        if format == Format::Ndjson {
+            self.print_event_lines(prev, &events, opts);
+            println!(
+                "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\"{}{},\"totals\":{},\"summary\":{}}}",
+                self.pid,
+                opts.time_format.format(prev.time),
+                opts.time_format.format(self.time),
+                opts.interval_json(prev.time, self.time),
+                more_json,
+                totals,
+                summary.to_json()
+            );
+            return true;
+        }
+        if format == Format::Json {
+            println!(
+                "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\"{},\"events\":[{}]{},\"totals\":{},\"summary\":{}}}",
+                self.pid,
+                opts.time_format.format(prev.time),
+                opts.time_format.format(self.time),
+                opts.interval_json(prev.time, self.time),
+                events.join(","),
+                more_json,
+                totals,
+                summary.to_json()
+            );
+            return true;
+        }
+        let was = |value: u64, prev: u64| -> String {
+            if value != prev {
+                format!(
+                    " (was {} diff {})",
+                    format_total(prev, opts.human),
+                    format_kb_diff(value as i64 - prev as i64, opts.human)
+                )
+            } else {
+                "".to_string()
+            }
+        };
+        // A growing number of maps can be a leak by itself, so it stands
+        // out in red when colors are on:
+        let maps_diff = if maps != maps_prev {
+            let diff = format!(
+                "(was {} diff {:+})",
+                maps_prev,
+                maps as i64 - maps_prev as i64
+            );
+            if maps > maps_prev && opts.color {
+                format!(" \x1b[31m{}\x1b[0m", diff)
+            } else {
+                format!(" {}", diff)
+            }
+        } else {
+            "".to_string()
+        };
+        // Swapping is shown once there is any, it is often the first sign
+        // of a machine running out of memory:
+        let swap_totals = if swap != 0 || swap_prev != 0 {
+            format!(
+                ", swap: {}{}, swap pss: {}{}",
+                format_total(swap, opts.human),
+                was(swap, swap_prev),
+                format_total(swap_pss, opts.human),
+                was(swap_pss, swap_pss_prev)
+            )
+        } else {
+            "".to_string()
+        };
+        // Like swap, lazy_free pages are only shown once there are any:
+        let lazy_free_totals = if lazy_free != 0 || lazy_free_prev != 0 {
+            format!(
+                ", lazy free: {}{}",
+                format_total(lazy_free, opts.human),
+                was(lazy_free, lazy_free_prev)
+            )
+        } else {
+            "".to_string()
+        };
+        println!(
+            "Total size: {}{}, anon rss: {}{}, file rss: {}{}, mmapped files: {}{}, mmapped rss: {}{}, maps: {}{}, largest gap: {}{}{}{}",
+            format_total(total_size, opts.human),
+            was(total_size, total_size_prev),
+            format_total(anon_rss, opts.human),
+            was(anon_rss, anon_rss_prev),
+            format_total(file_rss, opts.human),
+            was(file_rss, file_rss_prev),
+            format_total(total_mmapped, opts.human),
+            was(total_mmapped, total_mmapped_prev),
+            format_total(total_mmapped_rss, opts.human),
+            was(total_mmapped_rss, total_mmapped_rss_prev),
+            maps,
+            maps_diff,
+            format_total(gap, opts.human),
+            was(gap, gap_prev),
+            swap_totals,
+            lazy_free_totals,
+        );
+        if opts.hugepage_summary {
+            println!("{}", self.huge_page_line(Some(prev), opts));
+        }
+        if opts.working_set {
+            println!(
+                "Working set: {} referenced since the last sample",
+                format_total(self.total(Metric::Referenced), opts.human)
+            );
+        }
+        if let Some(note) = self.lazy_free_note(opts) {
+            println!("{}", note);
+        }
+        summary.print(opts.human);
+        true
+    }
+}
+
+/// A map whose rss grew in each of the last samples of the leak window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leak {
+    pub pid: i32,
+    pub map: Map,
+    pub samples: usize,
+    pub rate: f64, // average growth in kB/s over the window
+}
+
+/// Remembers the rss of every map over the last samples to spot maps
+/// which keep growing. Maps are identified by pid, start address and
+/// name, the history of maps which are gone is forgotten.
+pub struct LeakTracker {
+    window: usize,
+    history: HashMap<(i32, u64, String), Vec<(SystemTime, u64)>>,
+}
+
+impl LeakTracker {
+    /// A map is suspected once its rss increased in each of the last
+    /// `window` samples.
+    pub fn new(window: usize) -> LeakTracker {
+        LeakTracker {
+            window,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Records a new snapshot and returns the maps suspected to leak.
+    pub fn update(&mut self, maps: &Maps) -> Vec<Leak> {
+        let mut leaks = vec![];
+        let mut seen: HashSet<(i32, u64, String)> = HashSet::new();
+        for m in maps.maps.iter() {
+            let key = (maps.pid, m.start, m.name.clone());
+            let samples = self.history.entry(key.clone()).or_default();
+            samples.push((maps.time, m.rss));
+            if samples.len() > self.window + 1 {
+                samples.remove(0);
+            }
+            seen.insert(key);
+            if samples.len() == self.window + 1 && samples.windows(2).all(|w| w[1].1 > w[0].1) {
+                let (first_time, first_rss) = samples[0];
+                let secs = maps
+                    .time
+                    .duration_since(first_time)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                let rate = if secs > 0.0 {
+                    (m.rss - first_rss) as f64 / secs
+                } else {
+                    0.0
+                };
+                leaks.push(Leak {
+                    pid: maps.pid,
+                    map: m.clone(),
+                    samples: self.window,
+                    rate,
+                });
+            }
+        }
+        self.history
+            .retain(|key, _| key.0 != maps.pid || seen.contains(key));
+        leaks
+    }
+
+    /// Drops the history of a process which is no longer watched.
+    pub fn forget(&mut self, pid: i32) {
+        self.history.retain(|key, _| key.0 != pid);
+    }
+}
+
+impl Leak {
+    pub fn print(&self, opts: &Options) {
+        match opts.format {
+            Format::Text => println!(
+                "LEAK? {:x}-{:x} rss={} grew in each of the last {} samples, {} {}",
+                self.map.start,
+                self.map.end,
+                format_kb(self.map.rss, opts.human),
+                self.samples,
+                format_rate(self.rate, opts.human),
+                self.map.name
+            ),
+            Format::Json | Format::Ndjson => println!(
+                concat!(
+                    "{{\"pid\":{},\"event\":\"LEAK?\",\"range\":\"{:x}-{:x}\",",
+                    "\"rss\":{},\"samples\":{},\"rate_kb_per_s\":{:.1},\"name\":{}}}"
+                ),
+                self.pid,
+                self.map.start,
+                self.map.end,
+                self.map.rss,
+                self.samples,
+                self.rate,
+                json_string(&self.map.name)
+            ),
+            Format::Csv => {}
+        }
+    }
+}
+
+/// Which way a moving average went compared to the one a sample before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trend {
+    Rising,
+    Falling,
+    Stable,
+}
+
+impl Trend {
+    /// Changes within 1% of the average count as stable, to filter out
+    /// the jitter which the averaging leaves.
+    fn of(average: f64, prev: f64) -> Trend {
+        if (average - prev).abs() <= average.max(prev) / 100.0 {
+            Trend::Stable
+        } else if average > prev {
+            Trend::Rising
+        } else {
+            Trend::Falling
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trend::Rising => "rising",
+            Trend::Falling => "falling",
+            Trend::Stable => "stable",
+        }
+    }
+}
+
+/// The moving averages of the rss and pss of a map over the last samples
+/// of the averaging window, with their trends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Average {
+    pub pid: i32,
+    pub map: Map,
+    pub samples: usize,
+    pub rss: f64, // in kB
+    pub pss: f64,
+    pub rss_trend: Trend,
+    pub pss_trend: Trend,
+}
+
+/// Remembers the rss and pss of every map over the last samples for
+/// `--avg-window`, keyed like in `LeakTracker`.
+pub struct AverageTracker {
+    window: usize,
+    history: HashMap<(i32, u64, String), Vec<(u64, u64)>>,
+}
+
+impl AverageTracker {
+    /// Averages over `window` samples, the trend compares with the
+    /// average of the `window` samples before the last one.
+    pub fn new(window: usize) -> AverageTracker {
+        AverageTracker {
+            window,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Records a new snapshot and returns the averages of all maps with
+    /// a long enough history.
+    pub fn update(&mut self, maps: &Maps) -> Vec<Average> {
+        let mut averages = vec![];
+        let mut seen: HashSet<(i32, u64, String)> = HashSet::new();
+        let window = self.window;
+        let mean = |values: &[(u64, u64)], f: fn(&(u64, u64)) -> u64| -> f64 {
+            values.iter().map(f).sum::<u64>() as f64 / window as f64
+        };
+        for m in maps.maps.iter() {
+            let key = (maps.pid, m.start, m.name.clone());
+            let samples = self.history.entry(key.clone()).or_default();
+            samples.push((m.rss, m.pss));
+            if samples.len() > window + 1 {
+                samples.remove(0);
+            }
+            seen.insert(key);
+            if samples.len() == window + 1 {
+                let (prev, last) = (&samples[..window], &samples[1..]);
+                let (rss, pss) = (mean(last, |s| s.0), mean(last, |s| s.1));
+                averages.push(Average {
+                    pid: maps.pid,
+                    map: m.clone(),
+                    samples: window,
+                    rss,
+                    pss,
+                    rss_trend: Trend::of(rss, mean(prev, |s| s.0)),
+                    pss_trend: Trend::of(pss, mean(prev, |s| s.1)),
+                });
+            }
+        }
+        self.history
+            .retain(|key, _| key.0 != maps.pid || seen.contains(key));
+        averages
+    }
+
+    /// Drops the history of a process which is no longer watched.
+    pub fn forget(&mut self, pid: i32) {
+        self.history.retain(|key, _| key.0 != pid);
+    }
+}
+
+impl Average {
+    /// Tells if the rss or pss is going somewhere, the stable maps are
+    /// not shown just like unchanged ones in a diff.
+    pub fn is_moving(&self) -> bool {
+        self.rss_trend != Trend::Stable || self.pss_trend != Trend::Stable
+    }
+
+    pub fn print(&self, opts: &Options) {
+        let kb = |v: f64| {
+            if opts.human {
+                format_kb(v.round() as u64, true)
+            } else {
+                format!("{:.1}", v)
+            }
+        };
+        match opts.format {
+            Format::Text => println!(
+                "AVG: {:x}-{:x} rss={} ({}) pss={} ({}) over the last {} samples {}",
+                self.map.start,
+                self.map.end,
+                kb(self.rss),
+                self.rss_trend.name(),
+                kb(self.pss),
+                self.pss_trend.name(),
+                self.samples,
+                self.map.name
+            ),
+            Format::Json | Format::Ndjson => println!(
+                concat!(
+                    "{{\"pid\":{},\"event\":\"AVG\",\"range\":\"{:x}-{:x}\",",
+                    "\"rss\":{:.1},\"rss_trend\":\"{}\",\"pss\":{:.1},\"pss_trend\":\"{}\",",
+                    "\"samples\":{},\"name\":{}}}"
+                ),
+                self.pid,
+                self.map.start,
+                self.map.end,
+                self.rss,
+                self.rss_trend.name(),
+                self.pss,
+                self.pss_trend.name(),
+                self.samples,
+                json_string(&self.map.name)
+            ),
+            Format::Csv => {}
+        }
+    }
+}
+
+/// The aggregated totals from /proc/PID/smaps_rollup, which the kernel
+/// computes much more cheaply than the full per map smaps.
+#[derive(Debug, Default)]
+pub struct Rollup {
+    pub pid: i32,
+    pub time: Option<SystemTime>,
+    pub rss: u64, // all in kB
+    pub pss: u64,
+    pub pss_dirty: u64,
+    pub pss_anon: u64,
+    pub pss_file: u64,
+    pub pss_shmem: u64,
+    pub shared_clean: u64,
+    pub shared_dirty: u64,
+    pub private_clean: u64,
+    pub private_dirty: u64,
+    pub referenced: u64,
+    pub anonymous: u64,
+    pub lazy_free: u64,
+    pub anon_huge_pages: u64,
+    pub shmem_pmd_mapped: u64,
+    pub file_pmd_mapped: u64,
+    pub shared_huge_tlb: u64,
+    pub private_huge_tlb: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+    pub locked: u64,
+}
+
+impl Rollup {
+    pub fn get_rollup(pid: i32) -> Result<Rollup, MapError> {
+        let filename = format!("/proc/{}/smaps_rollup", pid);
+        let contents =
+            fs::read_to_string(&filename).map_err(|e| MapError::of_process(pid, &filename, e))?;
+        Rollup::from_str(&contents, pid)
+    }
+
+    pub fn from_str(contents: &str, pid: i32) -> Result<Rollup, MapError> {
+        let mut res = Rollup {
+            pid,
+            time: Some(SystemTime::now()),
+            ..Default::default()
+        };
+        // The first line is the address range header, which we ignore:
+        for line in contents.lines().skip(1) {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 2 {
+                continue;
+            }
+            let value = parts[1]
+                .parse::<u64>()
+                .map_err(|_| MapError::parse(line, "Expecting a number in second place"))?;
+            let field = match parts[0] {
+                "Rss:" => &mut res.rss,
+                "Pss:" => &mut res.pss,
+                "Pss_Dirty:" => &mut res.pss_dirty,
+                "Pss_Anon:" => &mut res.pss_anon,
+                "Pss_File:" => &mut res.pss_file,
+                "Pss_Shmem:" => &mut res.pss_shmem,
+                "Shared_Clean:" => &mut res.shared_clean,
+                "Shared_Dirty:" => &mut res.shared_dirty,
+                "Private_Clean:" => &mut res.private_clean,
+                "Private_Dirty:" => &mut res.private_dirty,
+                "Referenced:" => &mut res.referenced,
+                "Anonymous:" => &mut res.anonymous,
+                "LazyFree:" => &mut res.lazy_free,
+                "AnonHugePages:" => &mut res.anon_huge_pages,
+                "ShmemPmdMapped:" => &mut res.shmem_pmd_mapped,
+                "FilePmdMapped:" => &mut res.file_pmd_mapped,
+                "Shared_Hugetlb:" => &mut res.shared_huge_tlb,
+                "Private_Hugetlb:" => &mut res.private_huge_tlb,
+                "Swap:" => &mut res.swap,
+                "SwapPss:" => &mut res.swap_pss,
+                "Locked:" => &mut res.locked,
+                _ => continue,
+            };
+            *field = value;
+        }
+        Ok(res)
+    }
+
+    pub fn values(&self) -> [(&'static str, u64); 21] {
+        [
+            ("rss", self.rss),
+            ("pss", self.pss),
+            ("pss_dirty", self.pss_dirty),
+            ("pss_anon", self.pss_anon),
+            ("pss_file", self.pss_file),
+            ("pss_shmem", self.pss_shmem),
+            ("shared_clean", self.shared_clean),
+            ("shared_dirty", self.shared_dirty),
+            ("private_clean", self.private_clean),
+            ("private_dirty", self.private_dirty),
+            ("referenced", self.referenced),
+            ("anonymous", self.anonymous),
+            ("lazy_free", self.lazy_free),
+            ("anon_huge_pages", self.anon_huge_pages),
+            ("shmem_pmd_mapped", self.shmem_pmd_mapped),
+            ("file_pmd_mapped", self.file_pmd_mapped),
+            ("shared_huge_tlb", self.shared_huge_tlb),
+            ("private_huge_tlb", self.private_huge_tlb),
+            ("swap", self.swap),
+            ("swap_pss", self.swap_pss),
+            ("locked", self.locked),
+        ]
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time.unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    pub fn pretty_print(&self, opts: &Options) {
+        match opts.format {
+            Format::Text => {
+                let values: Vec<String> = self
+                    .values()
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, format_kb(*value, opts.human)))
+                    .collect();
+                println!("Rollup of pid {}: {}", self.pid, values.join(" "));
+            }
+            Format::Json | Format::Ndjson => {
+                let values: Vec<String> = self
+                    .values()
+                    .iter()
+                    .map(|(name, value)| format!("\"{}\":{}", name, value))
+                    .collect();
+                println!(
+                    "{{\"pid\":{},\"time\":\"{}\",\"rollup\":{{{}}}}}",
+                    self.pid,
+                    opts.time_format.format(self.time()),
+                    values.join(",")
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+
+    pub fn print_diff(&self, prev: &Rollup, opts: &Options) {
+        let changes: Vec<(&str, u64, u64)> = self
+            .values()
+            .into_iter()
+            .zip(prev.values())
+            .filter(|((_, new), (_, old))| new != old)
+            .map(|((name, new), (_, old))| (name, new, old))
+            .collect();
+        match opts.format {
+            Format::Text => {
+                println!(
+                    "\nDifferences in rollup of pid {} between {} and {}:",
+                    self.pid,
+                    opts.time_format.format(prev.time()),
+                    opts.time_format.format(self.time()),
+                );
+                if let Some(interval) = opts.interval(prev.time(), self.time()) {
+                    println!("{}", interval);
+                }
+                for (name, new, old) in changes {
+                    println!(
+                        "{}={} (was {} diff {})",
+                        name,
+                        format_total(new, opts.human),
+                        format_total(old, opts.human),
+                        format_kb_diff(new as i64 - old as i64, opts.human)
+                    );
+                }
+            }
+            Format::Json | Format::Ndjson => {
+                let changes: Vec<String> = changes
+                    .iter()
+                    .map(|(name, new, old)| {
+                        format!("\"{}\":{{\"value\":{},\"prev\":{}}}", name, new, old)
+                    })
+                    .collect();
+                println!(
+                    "{{\"pid\":{},\"from\":\"{}\",\"to\":\"{}\"{},\"rollup_changes\":{{{}}}}}",
+                    self.pid,
+                    opts.time_format.format(prev.time()),
+                    opts.time_format.format(self.time()),
+                    opts.interval_json(prev.time(), self.time()),
+                    changes.join(",")
+                );
+            }
+            Format::Csv => {}
+        }
+    }
+}
+
+/// Prints the selected metrics summed up over all processes of a cgroup,
+/// compared to the previous sample `prev`.
+pub fn print_cgroup_totals(cgroup: &str, snapshots: &[Maps], prev: &[Maps], opts: &Options) {
+    let sum = |snapshots: &[Maps], metric: Metric| -> u64 {
+        snapshots.iter().map(|maps| maps.total(metric)).sum()
+    };
+    let time = snapshots
+        .iter()
+        .map(|maps| maps.time)
+        .max()
+        .unwrap_or_else(SystemTime::now);
+    match opts.format {
+        Format::Text => {
+            let mut values: Vec<String> = vec![];
+            for &metric in opts.metrics.iter() {
+                let (value, was) = (sum(snapshots, metric), sum(prev, metric));
+                let diff = if value != was {
+                    format!(
+                        " (diff {})",
+                        format_kb_diff(value as i64 - was as i64, opts.human)
+                    )
+                } else {
+                    "".to_string()
+                };
+                values.push(format!(
+                    "{}={}{}",
+                    metric.name(),
+                    format_total(value, opts.human),
+                    diff
+                ));
+            }
+            let processes = if snapshots.len() != prev.len() {
+                format!("{} (was {})", snapshots.len(), prev.len())
+            } else {
+                snapshots.len().to_string()
+            };
+            println!(
+                "Totals for cgroup {}: processes={} {}",
+                cgroup,
+                processes,
+                values.join(" ")
+            );
+        }
+        Format::Json | Format::Ndjson => {
+            let values = |snapshots: &[Maps]| -> String {
+                opts.metrics
+                    .iter()
+                    .map(|&metric| format!("\"{}\":{}", metric.name(), sum(snapshots, metric)))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            };
+            println!(
+                concat!(
+                    "{{\"cgroup\":{},\"time\":\"{}\",\"processes\":{},\"prev_processes\":{},",
+                    "\"totals\":{{{}}},\"prev_totals\":{{{}}}}}"
+                ),
+                json_string(cgroup),
+                opts.time_format.format(time),
+                snapshots.len(),
+                prev.len(),
+                values(snapshots),
+                values(prev)
+            );
+        }
+        Format::Csv => {}
+    }
+}
+
+/// Prints the `n` processes whose rss grew the most from the rollups in
+/// `prev` to those in `now`, for `--all`. Processes in only one of them
+/// started or exited in between and are left out.
+pub fn print_top_growers(now: &[Rollup], prev: &[Rollup], n: usize, opts: &Options) {
+    let prev_rss: HashMap<i32, u64> = prev.iter().map(|r| (r.pid, r.rss)).collect();
+    let mut growers: Vec<(&Rollup, u64)> = now
+        .iter()
+        .filter_map(|r| Some((r, *prev_rss.get(&r.pid)?)))
+        .filter(|(r, was)| r.rss > *was)
+        .collect();
+    growers.sort_by_key(|(r, was)| std::cmp::Reverse(r.rss - was));
+    growers.truncate(n);
+    let comm = |pid: i32| -> String {
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+        comm.trim_end().to_string()
+    };
+    let time = |rollups: &[Rollup]| {
+        let time = rollups.iter().map(Rollup::time).max();
+        opts.time_format
+            .format(time.unwrap_or_else(SystemTime::now))
+    };
+    match opts.format {
+        Format::Text => {
+            println!(
+                "\nTop rss growers between {} and {} of {} processes:",
+                time(prev),
+                time(now),
+                now.len()
+            );
+            if growers.is_empty() {
+                println!("No process grew.");
+                return;
+            }
+            println!("{:>8} {:>14} {:>14}  NAME", "PID", "RSS", "GROWTH");
+            for (r, was) in growers {
+                println!(
+                    "{:>8} {:>14} {:>14}  {}",
+                    r.pid,
+                    format_total(r.rss, opts.human),
+                    format_kb_diff((r.rss - was) as i64, opts.human),
+                    comm(r.pid)
+                );
+            }
+        }
+        Format::Json | Format::Ndjson => {
+            let growers: Vec<String> = growers
+                .iter()
+                .map(|(r, was)| {
+                    format!(
+                        "{{\"pid\":{},\"name\":{},\"rss\":{},\"prev_rss\":{}}}",
+                        r.pid,
+                        json_string(&comm(r.pid)),
+                        r.rss,
+                        was
+                    )
+                })
+                .collect();
+            println!(
+                "{{\"from\":\"{}\",\"to\":\"{}\",\"processes\":{},\"growers\":[{}]}}",
+                time(prev),
+                time(now),
+                now.len(),
+                growers.join(",")
+            );
+        }
+        Format::Csv => {}
+    }
+}
+
+/// Renders the snapshots in the Prometheus text exposition format, with
+/// the selected metrics summed up per map name and in total per pid.
+pub fn to_prometheus(snapshots: &[Maps], opts: &Options) -> String {
+    prometheus_text(snapshots, opts, false)
+}
+
+/// Like `to_prometheus`, but every sample has the time of its snapshot
+/// as timestamp, for a recorded timeline of several snapshots per pid.
+pub fn to_prometheus_timeline(snapshots: &[Maps], opts: &Options) -> String {
+    prometheus_text(snapshots, opts, true)
+}
+
+fn prometheus_text(snapshots: &[Maps], opts: &Options, timestamps: bool) -> String {
+    // Prometheus takes timestamps in milliseconds since the epoch:
+    let at = |maps: &Maps| -> String {
+        if timestamps {
+            let ms = maps
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            format!(" {}", ms)
+        } else {
+            "".to_string()
+        }
+    };
+    let mut out = String::new();
+    for metric in opts.metrics.iter() {
+        out.push_str(&format!(
+            "# HELP mapwatcher_{0}_kb {0} of all maps with the same name in kB.\n",
+            metric.name()
+        ));
+        out.push_str(&format!("# TYPE mapwatcher_{}_kb gauge\n", metric.name()));
+        for maps in snapshots {
+            let groups = maps.groups(GroupBy::Name, opts);
+            for key in sorted_keys(&groups, &HashMap::new()) {
+                out.push_str(&format!(
+                    "mapwatcher_{}_kb{{pid=\"{}\",name=\"{}\"}} {}{}\n",
+                    metric.name(),
+                    maps.pid,
+                    prometheus_label(&key),
+                    groups[&key].value(*metric),
+                    at(maps)
+                ));
+            }
+        }
+        out.push_str(&format!(
+            "# HELP mapwatcher_total_{0}_kb {0} of all maps of the process in kB.\n",
+            metric.name()
+        ));
+        out.push_str(&format!(
+            "# TYPE mapwatcher_total_{}_kb gauge\n",
+            metric.name()
+        ));
+        for maps in snapshots {
+            out.push_str(&format!(
+                "mapwatcher_total_{}_kb{{pid=\"{}\"}} {}{}\n",
+                metric.name(),
+                maps.pid,
+                maps.total(*metric),
+                at(maps)
+            ));
+        }
+    }
+    out.push_str("# HELP mapwatcher_maps Number of maps of the process.\n");
+    out.push_str("# TYPE mapwatcher_maps gauge\n");
+    for maps in snapshots {
+        out.push_str(&format!(
+            "mapwatcher_maps{{pid=\"{}\"}} {}{}\n",
+            maps.pid,
+            maps.maps.len(),
+            at(maps)
+        ));
+    }
+    out
+}
+
+/// Replaces the file at `path` with the Prometheus metrics of the
+/// snapshots. The file is written under a temporary name and then
+/// renamed, so that a scrape never sees half of it.
+pub fn write_prometheus(path: &str, snapshots: &[Maps], opts: &Options) -> Result<(), MapError> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, to_prometheus(snapshots, opts)).map_err(|e| MapError::io(&tmp, e))?;
+    fs::rename(&tmp, path).map_err(|e| MapError::io(path, e))
+}
+
+/// The first two bytes of every gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The snapshots of a recording, read and parsed one at a time, so that
+/// replaying a long recording does not need all of it in memory.
+pub struct Recording {
+    path: String,
+    reader: Box<dyn BufRead>,
+    gzip: Option<Child>, // decompresses into `reader`
+}
+
+impl Recording {
+    fn open(path: &str) -> Result<Recording, MapError> {
+        let file = fs::File::open(path).map_err(|e| MapError::io(path, e))?;
+        let mut reader = io::BufReader::new(file);
+        let start = reader.fill_buf().map_err(|e| MapError::io(path, e))?;
+        if !start.starts_with(&GZIP_MAGIC) {
+            return Ok(Recording {
+                path: path.to_string(),
+                reader: Box::new(reader),
+                gzip: None,
+            });
+        }
+        // gzip also handles the concatenated streams of a recording
+        // appended to several times:
+        let mut gzip = Command::new("gzip")
+            .arg("-dc")
+            .arg(path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| MapError::io("gzip", e))?;
+        let Some(stdout) = gzip.stdout.take() else {
+            return Err(MapError::Other(format!("Cannot decompress {}", path)));
+        };
+        Ok(Recording {
+            path: path.to_string(),
+            reader: Box::new(io::BufReader::new(stdout)),
+            gzip: Some(gzip),
+        })
+    }
+
+    /// The next line without its newline, `None` at the end.
+    fn next_line(&mut self) -> Result<Option<String>, MapError> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                line.truncate(line.trim_end_matches('\n').len());
+                Ok(Some(line))
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Err(MapError::Other(format!("File {} is no text", self.path)))
+            }
+            Err(e) => Err(MapError::io(&self.path, e)),
+        }
+    }
+
+    /// Checks at the end of a compressed recording that gzip succeeded.
+    fn finish(&mut self) -> Result<(), MapError> {
+        let Some(mut gzip) = self.gzip.take() else {
+            return Ok(());
+        };
+        let mut errors = String::new();
+        if let Some(mut stderr) = gzip.stderr.take() {
+            let _ = stderr.read_to_string(&mut errors);
+        }
+        let status = gzip.wait().map_err(|e| MapError::io("gzip", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(MapError::Other(format!(
+                "Cannot decompress {}: {}",
+                self.path,
+                errors.trim()
+            )))
+        }
+    }
+
+    /// Reads the next snapshot, a header line with pid and time and the
+    /// maps up to an empty line.
+    fn next_snapshot(&mut self) -> Result<Option<Maps>, MapError> {
+        let header = loop {
+            match self.next_line()? {
+                None => {
+                    self.finish()?;
+                    return Ok(None);
+                }
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => break line,
+            }
+        };
+        let mut body = String::new();
+        while let Some(line) = self.next_line()? {
+            if line.trim().is_empty() {
+                break;
+            }
+            body.push_str(&line);
+            body.push('\n');
+        }
+        let bad = || MapError::parse(&header, format!("Bad snapshot header in {}", self.path));
+        let mut parts = header
+            .strip_prefix(RECORD_HEADER)
+            .ok_or_else(bad)?
+            .split_whitespace();
+        let pid = parts
+            .next()
+            .and_then(|p| p.strip_prefix("pid="))
+            .and_then(|p| p.parse::<i32>().ok())
+            .ok_or_else(bad)?;
+        let time = parts
+            .next()
+            .and_then(|t| t.strip_prefix("time="))
+            .and_then(|t| t.split_once('.'))
+            .and_then(|(secs, nanos)| Some((secs.parse().ok()?, nanos.parse().ok()?)))
+            .ok_or_else(bad)?;
+        let mut maps = Maps::from_str(&body, pid)?;
+        maps.time = SystemTime::UNIX_EPOCH + Duration::new(time.0, time.1);
+        Ok(Some(maps))
+    }
+}
+
+impl Iterator for Recording {
+    type Item = Result<Maps, MapError>;
+
+    fn next(&mut self) -> Option<Result<Maps, MapError>> {
+        self.next_snapshot().transpose()
+    }
+}
+
+impl Drop for Recording {
+    /// Stops gzip if the recording was not read to its end.
+    fn drop(&mut self) {
+        if let Some(mut gzip) = self.gzip.take() {
+            let _ = gzip.kill();
+            let _ = gzip.wait();
+        }
+    }
+}
+
+/// Finds the one process whose `comm` (or the base name of its first
+/// command line argument, since `comm` is truncated) is `name`.
+pub fn find_process_by_name(name: &str) -> Result<i32, MapError> {
+    let mut found: Vec<i32> = vec![];
+    for pid in all_pids()? {
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+        let argv0 = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+        let argv0 = String::from_utf8_lossy(argv0);
+        let basename = argv0.rsplit('/').next().unwrap_or_default();
+        if comm.trim_end() == name || basename == name {
+            found.push(pid);
+        }
+    }
+    found.sort();
+    match found.len() {
+        0 => Err(MapError::Other(format!("No process named {} found", name))),
+        1 => Ok(found[0]),
+        _ => {
+            let pids: Vec<String> = found.iter().map(|p| p.to_string()).collect();
+            Err(MapError::Other(format!(
+                "Several processes named {} found: {}",
+                name,
+                pids.join(",")
+            )))
+        }
+    }
+}
+
+/// Lists the PIDs of all processes in /proc, except mapwatcher itself.
+pub fn all_pids() -> Result<Vec<i32>, MapError> {
+    let own_pid = std::process::id() as i32;
+    let entries = fs::read_dir("/proc").map_err(|e| MapError::io("/proc", e))?;
+    Ok(entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|&pid| pid != own_pid)
+        .collect())
+}
+
+/// A vanished /proc/PID directory means the process has exited, which
+/// is the usual reason for not being able to read its smaps any more.
+pub fn process_exists(pid: i32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Reads the PIDs of the processes in a cgroup from its `cgroup.procs`
+/// file, `path` can also be the cgroup directory. mapwatcher itself is
+/// left out in case it runs in the same cgroup.
+pub fn read_cgroup_pids(path: &str) -> Result<Vec<i32>, MapError> {
+    let mut file = Path::new(path).to_path_buf();
+    if file.is_dir() {
+        file.push("cgroup.procs");
+    }
+    let file = file.display().to_string();
+    let contents = fs::read_to_string(&file).map_err(|e| MapError::io(&file, e))?;
+    let own_pid = std::process::id() as i32;
+    let mut pids: Vec<i32> = vec![];
+    for line in contents.lines() {
+        let pid = line
+            .trim()
+            .parse::<i32>()
+            .map_err(|_| MapError::parse(line, format!("Bad PID in {}", file)))?;
+        if pid != own_pid {
+            pids.push(pid);
+        }
+    }
+    pids.sort();
+    Ok(pids)
+}
+
+/// Reads the start time of a process from field 22 of /proc/PID/stat, in
+/// clock ticks since boot. A different start time for the same PID means
+/// that the PID was reused by another process.
+pub fn process_start_time(pid: i32) -> Result<u64, MapError> {
+    let path = format!("/proc/{}/stat", pid);
+    let stat = fs::read_to_string(&path).map_err(|e| MapError::of_process(pid, &path, e))?;
+    // The command name in field 2 can contain spaces and parentheses:
+    let after_name = stat
+        .rfind(')')
+        .map(|i| &stat[i + 1..])
+        .ok_or_else(|| MapError::parse(&stat, format!("Bad {}", path)))?;
+    after_name
+        .split_whitespace()
+        .nth(19)
+        .and_then(|field| field.parse::<u64>().ok())
+        .ok_or_else(|| MapError::parse(&stat, format!("No start time in {}", path)))
+}
+
+extern "C" {
+    fn sysconf(name: i32) -> i64;
+}
+
+const SC_CLK_TCK: i32 = 2;
+
+/// What a PID stands for: the command line and start time of the
+/// process, for headers which still make sense after restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub cmdline: String, // the arguments joined by spaces
+    pub started: Option<SystemTime>,
+}
+
+impl ProcessInfo {
+    /// Reads /proc/PID/cmdline and the start time. Kernel threads have no
+    /// command line, then their name from /proc/PID/comm is shown in
+    /// brackets like `ps` does.
+    pub fn read(pid: i32) -> Result<ProcessInfo, MapError> {
+        let path = format!("/proc/{}/cmdline", pid);
+        let raw = fs::read(&path).map_err(|e| MapError::of_process(pid, &path, e))?;
+        let mut cmdline = raw
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        if cmdline.is_empty() {
+            let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+            cmdline = format!("[{}]", comm.trim_end());
+        }
+        Ok(ProcessInfo {
+            pid,
+            cmdline,
+            started: process_started(pid).ok(),
+        })
+    }
+
+    pub fn print(&self, opts: &Options) {
+        let started = self.started.map(|t| opts.time_format.format(t));
+        match opts.format {
+            Format::Text => println!(
+                "Process {}: {}, started at {}",
+                self.pid,
+                self.cmdline,
+                started.as_deref().unwrap_or("an unknown time")
+            ),
+            Format::Json | Format::Ndjson => println!(
+                "{{\"pid\":{},\"cmdline\":{},\"started\":{}}}",
+                self.pid,
+                json_string(&self.cmdline),
+                started.map_or("null".to_string(), |t| format!("\"{}\"", t))
+            ),
+            Format::Csv => {}
+        }
+    }
+}
+
+/// A thread of a process, from /proc/PID/task/TID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thread {
+    pub tid: i32,
+    pub name: String,
+    pub stack_pointer: Option<u64>, // None while the thread is running
+}
+
+impl Thread {
+    /// Reads all threads of `pid`. The stack pointer comes from the
+    /// syscall file, whose last but one field it is while the thread is
+    /// blocked, which is when it can be read at all.
+    pub fn read_all(pid: i32) -> Result<Vec<Thread>, MapError> {
+        let dir = format!("/proc/{}/task", pid);
+        let entries = fs::read_dir(&dir).map_err(|e| MapError::of_process(pid, &dir, e))?;
+        let mut threads: Vec<Thread> = vec![];
+        for entry in entries.flatten() {
+            let Some(tid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<i32>().ok())
+            else {
+                continue;
+            };
+            // A thread which ends while we look at it is simply skipped:
+            let Ok(name) = fs::read_to_string(entry.path().join("comm")) else {
+                continue;
+            };
+            let syscall = fs::read_to_string(entry.path().join("syscall")).unwrap_or_default();
+            let fields: Vec<&str> = syscall.split_whitespace().collect();
+            let stack_pointer = match fields.len() {
+                n if n >= 3 => {
+                    let sp = fields[n - 2];
+                    u64::from_str_radix(sp.trim_start_matches("0x"), 16).ok()
+                }
+                _ => None,
+            };
+            threads.push(Thread {
+                tid,
+                name: name.trim_end().to_string(),
+                stack_pointer,
+            });
+        }
+        threads.sort_by_key(|t| t.tid);
+        Ok(threads)
+    }
+
+    /// Reports the thread with its stack map if known, `tag` is `THREAD`
+    /// for the threads found at the start and `NEW` or `GONE` later.
+    pub fn print(&self, tag: &str, pid: i32, stack: Option<&Map>, opts: &Options) {
+        match opts.format {
+            Format::Text => {
+                let stack = match stack {
+                    Some(m) => format!(
+                        "stack {:x}-{:x} size={} rss={}",
+                        m.start,
+                        m.end,
+                        format_kb(m.size, opts.human),
+                        format_kb(m.rss, opts.human)
+                    ),
+                    None => "stack unknown".to_string(),
+                };
+                println!(
+                    "{}: thread {} ({}) of process {}, {}",
+                    colored_tag(tag, opts),
+                    self.tid,
+                    self.name,
+                    pid,
+                    stack
+                );
+            }
+            Format::Json | Format::Ndjson => println!(
+                "{{\"pid\":{},\"event\":\"{}\",\"tid\":{},\"thread\":{},\"stack\":{}}}",
+                pid,
+                tag,
+                self.tid,
+                json_string(&self.name),
+                stack.map_or("null".to_string(), |m| format!(
+                    "{{\"range\":\"{:x}-{:x}\",\"size\":{},\"rss\":{}}}",
+                    m.start, m.end, m.size, m.rss
+                ))
+            ),
+            Format::Csv => {}
+        }
+    }
+}
+
+/// The wall clock time a process started, from its start time in clock
+/// ticks since boot and the boot time in /proc/stat.
+pub fn process_started(pid: i32) -> Result<SystemTime, MapError> {
+    let ticks = process_start_time(pid)?;
+    let stat = fs::read_to_string("/proc/stat").map_err(|e| MapError::io("/proc/stat", e))?;
+    let boot = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .ok_or_else(|| MapError::Other("No boot time in /proc/stat".to_string()))?;
+    // Safe, sysconf only reads a configuration value:
+    let ticks_per_sec = match unsafe { sysconf(SC_CLK_TCK) } {
+        n if n > 0 => n as u64,
+        _ => 100,
+    };
+    Ok(SystemTime::UNIX_EPOCH
+        + Duration::from_secs(boot)
+        + Duration::from_secs_f64(ticks as f64 / ticks_per_sec as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An unnamed map of 4 kB at page `page`, unnamed maps are never
+    /// taken as moved.
+    fn map(page: u64, rss: u64) -> Map {
+        Map {
+            start: page * 0x1000,
+            end: (page + 1) * 0x1000,
+            size: 4,
+            rss,
+            ..Default::default()
+        }
+    }
+
+    fn maps(maps: Vec<Map>) -> Maps {
+        Maps {
+            pid: 1,
+            time: SystemTime::UNIX_EPOCH,
+            maps,
+            first_seen: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parse_device_numbers() {
+        let m =
+            Map::parse_first_line("7f00-7f10 r--p 00000000 fd:01 1234 /usr/lib/libc.so.6").unwrap();
+        assert_eq!((m.device_major, m.device_minor), (0xfd, 0x01));
+        let m = Map::parse_first_line("7f00-7f10 rw-p 00000000 00:00 0").unwrap();
+        assert_eq!((m.device_major, m.device_minor), (0, 0));
+    }
+
+    #[test]
+    fn errors_tell_their_kind() {
+        let entry = "7f00-7f10 r--p 00000000 fd:01 1234 /lib/x.so\nRss:  many kB\n";
+        match Map::parse(entry) {
+            Err(MapError::Parse { line, .. }) => assert_eq!(line, "Rss:  many kB"),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(matches!(
+            Maps::get_maps(999_999_999),
+            Err(MapError::ProcessGone(999_999_999))
+        ));
+        assert!(matches!(
+            Maps::from_file("/nonexistent/smaps", 1),
+            Err(MapError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn label_unnamed_maps() {
+        let mut m = map(1, 4);
+        assert_eq!(m.labeled_name(), "<anon> (anon)");
+        m.name = "[heap]".to_string();
+        assert_eq!(m.labeled_name(), "[heap] (heap)");
+    }
+
+    #[test]
+    fn parse_names_with_spaces() {
+        let line = "7f00-7f10 r--p 00000000 fd:01 1234    /tmp/my  lib (1).so";
+        let m = Map::parse_first_line(line).unwrap();
+        assert_eq!(m.name, "/tmp/my  lib (1).so");
+        let m = Map::parse_first_line("7f00-7f10 rw-p 00000000 00:00 0 [stack:1234]").unwrap();
+        assert_eq!(m.name, "[stack:1234]");
+        let m = Map::parse_first_line("7f00-7f10 rw-p 00000000 00:00 0").unwrap();
+        assert_eq!(m.name, "");
+        let m = Map::parse_first_line("ff600000-ff601000 --xp [vsyscall]").unwrap();
+        assert_eq!(m.name, "[vsyscall]");
+        let mut m = map(1, 4);
+        m.flags = "r--p".to_string();
+        m.name = "/tmp/my  lib (1).so".to_string();
+        let again = Map::parse_first_line(m.to_smaps().lines().next().unwrap()).unwrap();
+        assert_eq!(again.name, m.name);
+    }
+
+    #[test]
+    fn parse_blank_first_line_as_error() {
+        assert!(matches!(
+            Map::parse_first_line(" \t "),
+            Err(MapError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_odd_device_column_as_zero() {
+        let m = Map::parse_first_line("7f00-7f10 r--p 00000000 xyz 1234 /lib/x.so").unwrap();
+        assert_eq!((m.device_major, m.device_minor), (0, 0));
+        assert_eq!(m.inode, 1234);
+        assert_eq!(m.name, "/lib/x.so");
+    }
+
+    #[test]
+    fn largest_gap_between_maps() {
+        let all = maps(vec![map(1, 4), map(2, 4), map(5, 4), map(7, 4)]);
+        assert_eq!(all.largest_gap(), 8);
+        assert_eq!(maps(vec![]).largest_gap(), 0);
+        // The hole above the heap does not count:
+        let mut heap = map(2, 4);
+        heap.name = "[heap]".to_string();
+        let mut stack = map(5000, 4);
+        stack.name = "[stack]".to_string();
+        let layout = maps(vec![map(1, 4), heap, map(1000, 4), map(1003, 4), stack]);
+        assert_eq!(layout.largest_gap(), 8);
+    }
+
+    #[test]
+    fn parse_entries_without_vmflags() {
+        let contents = concat!(
+            "7f00-7f10 r--p 00000000 fd:01 12 /lib/a.so\n",
+            "Rss: 8 kB\n",
+            "7f10-7f20 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+        );
+        let maps = Maps::from_str(contents, 1).unwrap();
+        assert_eq!(maps.maps.len(), 2);
+        assert_eq!((maps.maps[0].rss, maps.maps[1].rss), (8, 4));
+        assert!(maps.maps[0].vmflag_set.is_empty());
+    }
+
+    #[test]
+    fn parse_past_blank_lines() {
+        let contents = concat!(
+            "7f00-7f10 r--p 00000000 fd:01 12 /lib/a.so\n",
+            "Rss: 8 kB\n",
+            "\n",
+            "  \n",
+            "7f10-7f20 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+            "\n",
+        );
+        let maps = Maps::from_str(contents, 1).unwrap();
+        assert_eq!(maps.maps.len(), 2);
+        assert_eq!((maps.maps[0].rss, maps.maps[1].rss), (8, 4));
+    }
+
+    #[test]
+    fn parse_sorts_unsorted_maps() {
+        let sorted = concat!(
+            "7f00-7f10 r--p 00000000 fd:01 12 /lib/a.so\n",
+            "Rss: 8 kB\n",
+            "7f10-7f20 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+            "7f20-7f30 r--p 00000000 fd:01 13 /lib/b.so\n",
+            "Rss: 2 kB\n",
+        );
+        let unsorted = concat!(
+            "7f20-7f30 r--p 00000000 fd:01 13 /lib/b.so\n",
+            "Rss: 2 kB\n",
+            "7f00-7f10 r--p 00000000 fd:01 12 /lib/a.so\n",
+            "Rss: 8 kB\n",
+            "7f10-7f20 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+        );
+        let prev = Maps::from_str(sorted, 1).unwrap();
+        let now = Maps::from_str(unsorted, 1).unwrap();
+        assert!(now.is_sorted());
+        assert_eq!(now.diff(&prev), vec![]);
+        let now =
+            Maps::from_maps_str("7f10-7f20 rw-p 0 00:00 0\n7f00-7f10 r--p 0 00:00 0\n", 1).unwrap();
+        assert!(now.is_sorted());
+    }
+
+    #[test]
+    fn diff_identical() {
+        let a = maps(vec![map(1, 4), map(2, 4), map(3, 4)]);
+        assert_eq!(a.diff(&a.clone()), vec![]);
+    }
+
+    #[test]
+    fn diff_added_at_front() {
+        let prev = maps(vec![map(2, 4), map(3, 4)]);
+        let now = maps(vec![map(1, 4), map(2, 4), map(3, 4)]);
+        assert_eq!(now.diff(&prev), vec![DiffEvent::Mmap(map(1, 4))]);
+    }
+
+    #[test]
+    fn diff_dropped_at_end() {
+        let prev = maps(vec![map(1, 4), map(2, 4), map(3, 4)]);
+        let now = maps(vec![map(1, 4)]);
+        assert_eq!(
+            now.diff(&prev),
+            vec![DiffEvent::Drop(map(2, 4)), DiffEvent::Drop(map(3, 4))]
+        );
+    }
+
+    #[test]
+    fn diff_changed_in_middle() {
+        let prev = maps(vec![map(1, 4), map(2, 0), map(3, 4)]);
+        let now = maps(vec![map(1, 4), map(2, 4), map(3, 4)]);
+        assert_eq!(
+            now.diff(&prev),
+            vec![DiffEvent::Changed {
+                new: Box::new(map(2, 4)),
+                prev: Box::new(map(2, 0)),
+            }]
+        );
+    }
+
+    #[test]
+    fn diff_reports_all_drops_beyond_the_end() {
+        // `prev` goes on after the last map of `self`, all of its
+        // remaining maps must be reported, not just up to `self.maps.len()`:
+        let prev = maps(vec![map(1, 4), map(3, 4), map(4, 4), map(5, 4), map(6, 4)]);
+        let now = maps(vec![map(2, 4), map(3, 8)]);
+        assert_eq!(
+            now.diff(&prev),
+            vec![
+                DiffEvent::Drop(map(1, 4)),
+                DiffEvent::Mmap(map(2, 4)),
+                DiffEvent::Changed {
+                    new: Box::new(map(3, 8)),
+                    prev: Box::new(map(3, 4)),
+                },
+                DiffEvent::Drop(map(4, 4)),
+                DiffEvent::Drop(map(5, 4)),
+                DiffEvent::Drop(map(6, 4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_summary_counts_events_and_deltas() {
+        let mut before = map(3, 4);
+        before.pss = 2;
+        let mut grown = map(3, 8);
+        grown.pss = 7;
+        grown.swap = 4;
+        let prev = maps(vec![map(1, 4), before, map(4, 4)]);
+        let now = maps(vec![map(2, 4), grown, map(4, 4)]);
+        assert_eq!(
+            now.diff_summary(&prev),
+            DiffSummary {
+                added: 1,
+                dropped: 1,
+                changed: 1,
+                moved: 0,
+                rss_delta: 4,
+                pss_delta: 5,
+                swap_delta: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn rss_is_split_into_anonymous_and_file_backed() {
+        let mut file = map(2, 8);
+        file.inode = 1234;
+        file.name = "/usr/lib/libc.so.6".to_string();
+        let mut heap = map(3, 16);
+        heap.name = "[heap]".to_string();
+        assert_eq!(maps(vec![map(1, 4), file, heap]).rss_split(), (20, 8));
+    }
+
+    #[test]
+    fn match_substrings_and_globs() {
+        assert!(matches_pattern("/usr/lib/libc.so.6", "libc"));
+        assert!(!matches_pattern("/usr/lib/libc.so.6", "libm"));
+        assert!(matches_pattern("/memfd:jit-1234 (deleted)", "/memfd:jit-*"));
+        assert!(matches_pattern("[stack:42]", "[stack:??]"));
+        assert!(!matches_pattern("[stack:4]", "[stack:??]"));
+        assert!(matches_pattern("/a/b/c.so", "*/c.so"));
+        assert!(!matches_pattern("/a/b/c.so.1", "*/c.so"));
+        assert_eq!(
+            parse_patterns("# noise\n[stack\n\n  /memfd:* \n"),
+            vec!["[stack", "/memfd:*"]
+        );
+    }
+
+    #[test]
+    fn ages_are_carried_and_reset_for_new_maps() {
+        let first = maps(vec![map(1, 4), map(2, 4)]);
+        let mut second = maps(vec![map(1, 8), map(3, 4)]);
+        second.time = first.time + Duration::from_secs(10);
+        second.carry_ages(&first);
+        let mut third = maps(vec![map(1, 8), map(3, 4)]);
+        third.time = first.time + Duration::from_secs(25);
+        third.carry_ages(&second);
+        assert_eq!(third.age(&map(1, 8)), Duration::from_secs(25));
+        assert_eq!(third.age(&map(3, 4)), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn diff_empty_on_one_side() {
+        let empty = maps(vec![]);
+        let full = maps(vec![map(1, 4), map(2, 4)]);
+        assert_eq!(
+            full.diff(&empty),
+            vec![DiffEvent::Mmap(map(1, 4)), DiffEvent::Mmap(map(2, 4))]
+        );
+        assert_eq!(
+            empty.diff(&full),
+            vec![DiffEvent::Drop(map(1, 4)), DiffEvent::Drop(map(2, 4))]
+        );
+        assert_eq!(empty.diff(&empty.clone()), vec![]);
+    }
+
+    #[test]
+    fn diff_pairs_moved_maps() {
+        let mut old = map(1, 4);
+        old.flags = "rw-p".to_string();
+        old.name = "/memfd:pool".to_string();
+        let mut new = old.clone();
+        new.start = 8 * 0x1000;
+        new.end = 9 * 0x1000;
+        let prev = maps(vec![old.clone(), map(3, 4)]);
+        let now = maps(vec![map(3, 4), new.clone()]);
+        assert_eq!(
+            now.diff(&prev),
+            vec![DiffEvent::Moved {
+                new: Box::new(new),
+                prev: Box::new(old),
+            }]
+        );
+    }
+
+    /// A snapshot of one growing heap at `secs` seconds.
+    fn heap_at(secs: u64, rss: u64) -> Maps {
+        let mut heap = map(1, rss);
+        heap.name = "[heap]".to_string();
+        let mut snapshot = maps(vec![heap]);
+        snapshot.time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        snapshot
+    }
+
+    #[test]
+    fn leak_tracker_flags_steady_growth() {
+        let mut tracker = LeakTracker::new(3);
+        for (secs, rss) in [(0, 4), (10, 8), (20, 12)] {
+            assert_eq!(tracker.update(&heap_at(secs, rss)), vec![]);
+        }
+        let leaks = tracker.update(&heap_at(30, 16));
+        assert_eq!(leaks.len(), 1);
+        assert_eq!((leaks[0].map.rss, leaks[0].samples), (16, 3));
+        assert!((leaks[0].rate - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn leak_tracker_resets_after_a_drop() {
+        let mut tracker = LeakTracker::new(3);
+        for (secs, rss) in [(0, 4), (10, 8), (20, 12), (30, 16)] {
+            tracker.update(&heap_at(secs, rss));
+        }
+        // After shrinking it takes the whole window of growth again:
+        for (secs, rss) in [(40, 12), (50, 16), (60, 20)] {
+            assert_eq!(tracker.update(&heap_at(secs, rss)), vec![]);
+        }
+        assert_eq!(tracker.update(&heap_at(70, 24)).len(), 1);
+    }
+
+    #[test]
+    fn average_tracker_wraps_its_window() {
+        let mut tracker = AverageTracker::new(2);
+        assert_eq!(tracker.update(&heap_at(0, 10)), vec![]);
+        assert_eq!(tracker.update(&heap_at(10, 20)), vec![]);
+        let averages = tracker.update(&heap_at(20, 30));
+        assert_eq!(
+            (averages[0].rss, averages[0].rss_trend),
+            (25.0, Trend::Rising)
+        );
+        // The oldest sample falls out of the window:
+        let averages = tracker.update(&heap_at(30, 60));
+        assert_eq!(
+            (averages[0].rss, averages[0].rss_trend),
+            (45.0, Trend::Rising)
+        );
+        tracker.update(&heap_at(40, 60));
+        let averages = tracker.update(&heap_at(50, 60));
+        assert_eq!(
+            (averages[0].rss, averages[0].rss_trend),
+            (60.0, Trend::Stable)
+        );
+        assert_eq!(averages[0].samples, 2);
+        assert!(!averages[0].is_moving());
+    }
+
+    #[test]
+    fn parse_sizes_with_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("8kB"), Ok(8));
+        assert_eq!(parse_size("64M"), Ok(64 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1.5GiB"), Ok(1536 * 1024));
+        assert_eq!(parse_size("1t"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+    }
+
+    #[test]
+    fn format_kb_at_unit_boundaries() {
+        assert_eq!(format_kb(1024, false), "1024");
+        assert_eq!(format_kb(0, true), "0.0 KiB");
+        assert_eq!(format_kb(1023, true), "1023.0 KiB");
+        assert_eq!(format_kb(1024, true), "1.0 MiB");
+        assert_eq!(format_kb(1536, true), "1.5 MiB");
+        assert_eq!(format_kb(1024 * 1024 - 1, true), "1024.0 MiB");
+        assert_eq!(format_kb(1024 * 1024, true), "1.0 GiB");
+        assert_eq!(format_kb(1 << 40, true), "1.0 PiB");
+        assert_eq!(format_kb(1 << 50, true), "1024.0 PiB");
+    }
+
+    #[test]
+    fn recordings_read_back_what_was_recorded() {
+        let contents = concat!(
+            "7f00-7f10 r--p 00000000 fd:01 12 /lib/a.so\n",
+            "Rss: 8 kB\n",
+            "Pss: 4 kB\n",
+            "VmFlags: rd mr mw me\n",
+            "7f10-7f20 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+            "Swap: 2 kB\n",
+        );
+        let mut first = Maps::from_str(contents, 42).unwrap();
+        first.time = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut second = first.clone();
+        second.maps[1].rss = 12;
+        second.time += Duration::from_secs(2);
+        let path = std::env::temp_dir().join(format!("mapwatcher-test-{}.rec", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        first.record(path).unwrap();
+        second.record(path).unwrap();
+        let snapshots: Vec<Maps> = Maps::read_recording(path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        for (read, recorded) in snapshots.iter().zip([&first, &second]) {
+            assert_eq!((read.pid, read.time), (recorded.pid, recorded.time));
+            assert_eq!(read.maps, recorded.maps);
+        }
+    }
+
+    #[test]
+    fn parse_rollup() {
+        let contents = concat!(
+            "55d0c0a5e000-7ffd2a1f3000 ---p 00000000 00:00 0                          [rollup]\n",
+            "Rss:               12345 kB\n",
+            "Pss:                6789 kB\n",
+            "Pss_Dirty:           900 kB\n",
+            "Pss_Anon:           4000 kB\n",
+            "Pss_File:           2789 kB\n",
+            "Private_Dirty:       960 kB\n",
+            "Swap:                 16 kB\n",
+            "SwapPss:               8 kB\n",
+            "Locked:                0 kB\n",
+        );
+        let r = Rollup::from_str(contents, 7).unwrap();
+        assert_eq!(r.pid, 7);
+        assert_eq!((r.rss, r.pss, r.pss_dirty), (12345, 6789, 900));
+        assert_eq!((r.pss_anon, r.pss_file, r.pss_shmem), (4000, 2789, 0));
+        assert_eq!((r.private_dirty, r.swap, r.swap_pss), (960, 16, 8));
+        assert!(matches!(
+            Rollup::from_str("header\nRss: many kB\n", 7),
+            Err(MapError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_fields_by_key_in_any_order() {
+        let entry = concat!(
+            "7f00-7f10 rw-p 00000000 00:00 0 [heap]\n",
+            "Swap:                  2 kB\n",
+            "Pss:                   6 kB\n",
+            "FutureField:          99 kB\n",
+            "Private_Dirty:         5 kB\n",
+            "Rss:                   8 kB\n",
+            "Size:                 64 kB\n",
+        );
+        let m = Map::parse(entry).unwrap().unwrap();
+        assert_eq!((m.size, m.rss, m.pss), (64, 8, 6));
+        assert_eq!((m.private_dirty, m.swap), (5, 2));
+        assert_eq!((m.shared_clean, m.locked), (0, 0));
+    }
+
+    #[test]
+    fn parse_vmflags_into_a_set() {
+        let entry = concat!(
+            "7f00-7f10 rw-p 00000000 00:00 0\n",
+            "Rss: 4 kB\n",
+            "VmFlags: rd wr mr mw me ac sd\n",
+        );
+        let m = Map::parse(entry).unwrap().unwrap();
+        assert_eq!(m.vmflags, "VmFlags: rd wr mr mw me ac sd");
+        let expected: HashSet<String> = ["rd", "wr", "mr", "mw", "me", "ac", "sd"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        assert_eq!(m.vmflag_set, expected);
+        assert!(!m.vmflag_set.contains("ex"));
+    }
+}
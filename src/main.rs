@@ -1,426 +1,1904 @@
-use num_format::{Locale, ToFormattedString};
+mod cli;
+
+use cli::{
+    parse_delay, parse_pids, usage, Args, EXIT_ALERT, EXIT_ERROR, EXIT_PARSE_ERROR,
+    EXIT_PERMISSION_DENIED, EXIT_PROCESS_EXITED, VERSION,
+};
+use mapwatcher::{
+    all_pids, colored_tag, find_process_by_name, format_duration, format_kb, format_total,
+    json_string, log, parse_patterns, print_cgroup_totals, print_top_growers, process_exists,
+    process_start_time, read_cgroup_pids, set_log_level, sparkline, to_prometheus_timeline,
+    write_prometheus, AverageTracker, Format, LeakTracker, Map, MapError, Maps, Metric, Options,
+    ProcessInfo, Rollup, Thread, CSV_HEADER,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, SystemTime};
-use time::{format_description, OffsetDateTime};
-
-// Some information I found on the internet for the fields:
-// See https://utcc.utoronto.ca/~cks/space/blog/linux/SmapsFields?showcomments#comments
-
-// For each VMA mapping that gets listed in smaps, the kernel walks all
-// of the PTEs associated with it and looks at all of the known pages. Each
-// PTE is then counted up:
-//
-//  - the full PTE size is counted as Rss.
-//  - if the page has been used recently, it's added to Referenced.
-//  - if the page is mapped in only one process it is labeled as private;
-//    its full size is added to Pss.
-// - if the page is mapped in more than one process it is shared and the
-//   amount it adds to Pss is divided by the number of processes that have it
-//   mapped.
-//
-// (If the PTE is for something in swap it only adds to the Swap size.)
-//
-// Note that a 'private' page is not quite as private as you might
-// think. Because processes map pages independently of each other, it's
-// possible to have a shared page that is currently mapped only by a single
-// process (eg only one process may have called an obscure libc function
-// recently); such pages are counted in 'private'.
-//
-// The Size of a mapping is how much address space it covers.
-//
-// If the mapping has been locked into memory via mlock() or the like,
-// Locked is the same as Pss (ie, it is this process's fair share of the
-// amount of locked memory for this mapping); otherwise it is 0 kB.
-
-#[derive(Debug)]
-struct Map {
-    pub start: u64,
-    end: u64,
-    pub flags: String,
-    pub offset: u64,       // what is this?
-    pub device_major: u32, // correct guess?
-    pub device_minor: u32, // correct guess?
-    pub inode: u64,        // what is this?
-    pub name: String,
-    pub size: u64, // all in kB
-    pub kernel_page_size: u64,
-    pub mmu_page_size: u64,
-    pub rss: u64,
-    pub pss: u64,
-    pub shared_clean: u64,
-    pub shared_dirty: u64,
-    pub private_clean: u64,
-    pub private_dirty: u64,
-    pub referenced: u64,
-    pub anonymous: u64,
-    pub lazy_free: u64,
-    pub anon_huge_pages: u64,
-    pub shmem_pmd_mapped: u64,
-    pub file_pmd_mapped: u64,
-    pub shared_huge_tlb: u64,
-    pub private_huge_tlb: u64,
-    pub swap: u64,
-    pub swap_pss: u64,
-    pub locked: u64,
-    pub thp_eligible: bool,
-    pub protection_key: u64,
-    pub vmflags: String,
-}
-
-impl Map {
-    pub fn parse_from_line_iterator(lines: &mut std::str::Lines) -> Result<Option<Map>, String> {
-        let get_line = |lines: &mut std::str::Lines, first| -> Result<String, String> {
-            let head = lines.next();
-            match head {
-                None => {
-                    if first {
-                        return Ok("".to_string());
-                    } else {
-                        return Err("Expecting more lines!".to_string());
+
+/// Observes only /proc/PID/smaps_rollup and prints the changes of the
+/// totals, for processes too big to walk all maps regularly. Returns the
+/// exit status if it stopped because no process is left.
+fn watch_rollup(
+    pids: &[i32],
+    pace: Pace,
+    max_iterations: Option<u64>,
+    opts: &Options,
+    alerts: &mut Alerts,
+    header: bool,
+    mut retries: Retries,
+) -> Option<i32> {
+    let mut status = EXIT_PROCESS_EXITED;
+    let mut prev_rollups: Vec<Rollup> = vec![];
+    let mut start_times = StartTimes::new();
+    let mut infos = ProcessInfos::new();
+    for &pid in pids {
+        match Rollup::get_rollup(pid) {
+            Ok(rollup) => prev_rollups.push(rollup),
+            Err(e) => initial_read_failed(pid, &e),
+        }
+        start_times.remember(pid);
+    }
+    for prev in prev_rollups.iter() {
+        infos.print(prev.pid, opts);
+        if !opts.quiet {
+            prev.pretty_print(opts);
+        }
+        alerts.check(prev.pid, prev.rss, prev.pss, prev.swap);
+    }
+    let mut remaining = max_iterations;
+    while !prev_rollups.is_empty() {
+        if remaining == Some(0) {
+            break;
+        }
+        remaining = remaining.map(|n| n - 1);
+        if !wait_for_sample(pace) {
+            break;
+        }
+        let watched = prev_rollups.len();
+        let mut next_rollups: Vec<Rollup> = vec![];
+        for prev in prev_rollups.into_iter() {
+            if start_times.exited(prev.pid) {
+                let consequence = consequence(watched, false, false);
+                log!(Warn, "process {} has exited, {}", prev.pid, consequence);
+                continue;
+            }
+            match Rollup::get_rollup(prev.pid) {
+                Ok(r) if start_times.reused(r.pid) => {
+                    report_reused(r.pid, consequence(watched, false, false))
+                }
+                Ok(r) => {
+                    retries.reset(r.pid);
+                    if header {
+                        infos.print(r.pid, opts);
                     }
+                    r.print_diff(&prev, opts);
+                    alerts.check(r.pid, r.rss, r.pss, r.swap);
+                    next_rollups.push(r);
+                }
+                Err(e) if retries.transient(prev.pid, &e) => next_rollups.push(prev),
+                Err(e) => {
+                    let failed =
+                        report_read_failure(prev.pid, &e, consequence(watched, false, false));
+                    status = status.max(failed);
                 }
-                Some(s) => Ok(s.to_string()),
             }
+        }
+        prev_rollups = next_rollups;
+    }
+    prev_rollups.is_empty().then_some(status)
+}
+
+/// Reads the rollups of all processes for `--all`, skipping those which
+/// cannot be read. `start_times` keeps the start time of every process,
+/// a process whose PID was reused is left out of `prev`.
+fn read_all_rollups(start_times: &mut HashMap<i32, u64>, prev: &mut Vec<Rollup>) -> Vec<Rollup> {
+    let pids = match all_pids() {
+        Ok(pids) => pids,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+    // Most processes of other users cannot be read, and any process may
+    // exit while we get to it:
+    let rollups: Vec<Rollup> = pids
+        .into_iter()
+        .filter_map(|pid| Rollup::get_rollup(pid).ok())
+        .collect();
+    for r in rollups.iter() {
+        let Ok(start_time) = process_start_time(r.pid) else {
+            continue;
         };
-        let first_line = get_line(lines, true)?;
-        if first_line.is_empty() {
-            return Ok(None);
+        if start_times
+            .insert(r.pid, start_time)
+            .is_some_and(|then| then != start_time)
+        {
+            prev.retain(|p| p.pid != r.pid);
+        }
+    }
+    start_times.retain(|pid, _| rollups.iter().any(|r| r.pid == *pid));
+    rollups
+}
+
+/// Watches the rollups of all processes on the machine and prints the
+/// `top` processes whose rss grew the most in each interval, to find the
+/// one which leaks.
+fn watch_all(pace: Pace, max_iterations: Option<u64>, top: usize, opts: &Options) {
+    let mut start_times: HashMap<i32, u64> = HashMap::new();
+    let mut prev = read_all_rollups(&mut start_times, &mut vec![]);
+    if opts.format == Format::Text {
+        println!("Watching {} processes...", prev.len());
+    }
+    let mut remaining = max_iterations;
+    while remaining != Some(0) && wait_for_sample(pace) {
+        remaining = remaining.map(|n| n - 1);
+        let now = read_all_rollups(&mut start_times, &mut prev);
+        print_top_growers(&now, &prev, top, opts);
+        prev = now;
+    }
+}
+
+/// Prints the snapshots of a recording made with `--record` like a live
+/// run would have, without waiting between them.
+fn replay(path: &str, opts: &Options) {
+    let recording_failed = |e: MapError| -> ! {
+        eprintln!("Could not read recording {}: {}", path, e);
+        std::process::exit(input_error_status(&e));
+    };
+    let snapshots = Maps::read_recording(path).unwrap_or_else(|e| recording_failed(e));
+    let mut prev_maps: Vec<Maps> = vec![];
+    for maps in snapshots {
+        let mut maps = maps.unwrap_or_else(|e| recording_failed(e));
+        match prev_maps.iter().position(|p| p.pid == maps.pid) {
+            Some(i) => {
+                maps.carry_ages(&prev_maps[i]);
+                maps.print_diff(&prev_maps[i], opts);
+                if opts.totals {
+                    maps.print_totals(opts);
+                }
+                prev_maps[i] = maps;
+            }
+            None => {
+                if !opts.quiet {
+                    if opts.format == Format::Text {
+                        println!("Got initial maps of process {}:", maps.pid);
+                    }
+                    maps.pretty_print(opts);
+                    maps.print_totals(opts);
+                }
+                prev_maps.push(maps);
+            }
         }
-        let items: Vec<String> = first_line.split_whitespace().map(str::to_string).collect();
-        if items.len() < 5 {
-            return Err(format!("Found strange first line: {}", &first_line));
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break;
         }
-        let bounds: Vec<String> = items[0].split("-").map(str::to_string).collect();
-        if bounds.len() != 2 {
-            return Err(format!("Found bad bounds: {}", items[0]));
+    }
+    if opts.format == Format::Text {
+        println!("Goodbye!");
+    }
+}
+
+/// Prints the snapshots of a recording made with `--record` as Prometheus
+/// metrics with timestamps or as CSV, for analyzing them offline.
+fn convert_recording(path: &str, target: &str, opts: &mut Options) {
+    let recording_failed = |e: MapError| -> ! {
+        eprintln!("Could not read recording {}: {}", path, e);
+        std::process::exit(input_error_status(&e));
+    };
+    let snapshots = Maps::read_recording(path).unwrap_or_else(|e| recording_failed(e));
+    if target == "prometheus" {
+        // The samples of each metric have to be together, so this needs
+        // all snapshots at once:
+        let snapshots: Vec<Maps> = snapshots
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|e| recording_failed(e));
+        print!("{}", to_prometheus_timeline(&snapshots, opts));
+        return;
+    }
+    opts.format = Format::Csv;
+    println!("{}", CSV_HEADER);
+    for maps in snapshots {
+        maps.unwrap_or_else(|e| recording_failed(e))
+            .pretty_print(opts);
+    }
+}
+
+/// Prints the current maps (or rollups) of the processes once, for
+/// `--once`.
+fn show_once(pids: &[i32], rollup: bool, opts: &Options) {
+    for &pid in pids {
+        if rollup {
+            match Rollup::get_rollup(pid) {
+                Ok(r) => r.pretty_print(opts),
+                Err(e) => initial_read_failed(pid, &e),
+            }
+            continue;
         }
-        let devices: Vec<String> = items[3].split(":").map(str::to_string).collect();
-        if devices.len() != 2 {
-            return Err(format!("Found bad devices: {}", items[3]));
+        match read_maps(pid, opts) {
+            Ok(maps) => {
+                if opts.format == Format::Text {
+                    println!("Maps of process {}:", pid);
+                }
+                maps.pretty_print(opts);
+                maps.print_totals(opts);
+            }
+            Err(e) => initial_read_failed(pid, &e),
+        }
+    }
+}
+
+/// Reads a captured smaps dump for `--diff` or exits. Its time is the
+/// time the file was last modified, which is when it was captured.
+fn read_dump(path: &str) -> Maps {
+    let mut maps = match Maps::from_file(path, 0) {
+        Ok(maps) => maps,
+        Err(e) => {
+            eprintln!("Could not read maps from {}: {}", path, e);
+            std::process::exit(input_error_status(&e));
+        }
+    };
+    if let Ok(modified) = fs::metadata(path).and_then(|m| m.modified()) {
+        maps.time = modified;
+    }
+    maps
+}
+
+/// The exit status for a file or smaps which could not be read or parsed.
+fn input_error_status(e: &MapError) -> i32 {
+    match e {
+        MapError::Parse { .. } => EXIT_PARSE_ERROR,
+        _ => EXIT_ERROR,
+    }
+}
+
+/// Thresholds for the total rss, pss and swap of a process, in kB, and
+/// for the growth of one of them in percent.
+struct Alerts {
+    rss: Option<u64>,
+    pss: Option<u64>,
+    swap: Option<u64>,
+    wx: bool,   // alert about writable and executable maps
+    exit: bool, // exit with status 5 after the first alert
+    growth_percent: Option<f64>,
+    growth_metric: Metric,         // rss, pss or swap
+    growth_since_start: bool,      // against the first sample, not the previous one
+    references: HashMap<i32, u64>, // the value the growth is measured from
+}
+
+impl Alerts {
+    /// Prints an alert to stderr for every threshold the totals of `pid`
+    /// exceed and exits if wanted.
+    fn check(&mut self, pid: i32, rss: u64, pss: u64, swap: u64) {
+        let mut alerted = false;
+        for (name, value, limit) in [
+            ("rss", rss, self.rss),
+            ("pss", pss, self.pss),
+            ("swap", swap, self.swap),
+        ] {
+            if let Some(limit) = limit.filter(|&limit| value > limit) {
+                eprintln!(
+                    "ALERT: total {} of process {} is {} kB ({}), above the threshold of {} kB ({})",
+                    name,
+                    pid,
+                    format_total(value, false),
+                    format_kb(value, true),
+                    format_total(limit, false),
+                    format_kb(limit, true)
+                );
+                alerted = true;
+            }
         }
-        let mut further_lines: Vec<String> = vec![];
-        loop {
-            further_lines.push(get_line(lines, false)?);
-            if further_lines.last().unwrap().starts_with("VmFlags") {
-                break;
+        if let Some(percent) = self.growth_percent {
+            let value = match self.growth_metric {
+                Metric::Pss => pss,
+                Metric::Swap => swap,
+                _ => rss,
+            };
+            let reference = *self.references.entry(pid).or_insert(value);
+            // Growing from nothing is no percentage:
+            let growth = (value as f64 / reference as f64 - 1.0) * 100.0;
+            if reference > 0 && growth > percent {
+                eprintln!(
+                    "ALERT: total {} of process {} grew by {:.1}% since the {} sample, from {} kB to {} kB, above the threshold of {}%",
+                    self.growth_metric.name(),
+                    pid,
+                    growth,
+                    if self.growth_since_start { "first" } else { "previous" },
+                    format_total(reference, false),
+                    format_total(value, false),
+                    percent
+                );
+                alerted = true;
+            }
+            if !self.growth_since_start {
+                self.references.insert(pid, value);
             }
         }
-        let get_number = |s: &String| -> u64 {
-            let parts: Vec<String> = s.split_whitespace().map(str::to_string).collect();
-            if parts.len() < 2 {
-                return 0;
+        if alerted && self.exit {
+            std::process::exit(EXIT_ALERT);
+        }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.references.remove(&pid);
+    }
+
+    /// Checks the totals of a snapshot and, with `--wx-alert`, prints a
+    /// violation for every map which is writable and executable at once.
+    fn check_maps(&mut self, maps: &Maps) {
+        if self.wx {
+            let mut alerted = false;
+            for m in maps.maps.iter().filter(|m| m.is_writable_executable()) {
+                eprintln!(
+                    "W^X VIOLATION: process {} has the writable and executable map {:x}-{:x} {} {}",
+                    maps.pid,
+                    m.start,
+                    m.end,
+                    m.flags,
+                    if m.name.is_empty() { "<anon>" } else { &m.name }
+                );
+                alerted = true;
             }
-            return parts[1]
-                .parse::<u64>()
-                .expect(&format!("Expecting a number in this string in second place: {}", s)[..]);
+            if alerted && self.exit {
+                std::process::exit(EXIT_ALERT);
+            }
+        }
+        self.check(
+            maps.pid,
+            maps.total(Metric::Rss),
+            maps.total(Metric::Pss),
+            maps.total(Metric::Swap),
+        );
+    }
+}
+
+/// The conditions of `--until-rss`, `--until-map-appears` and
+/// `--until-map-gone` which end the watching, the names are substrings
+/// like for `--filter`.
+struct Until {
+    rss: Option<u64>, // in kB
+    appears: Option<String>,
+    gone: Option<String>,
+    // If a map matched `appears` and `gone` in the last sample, by pid:
+    present: HashMap<i32, (bool, bool)>,
+}
+
+impl Until {
+    /// Explains which condition a snapshot fulfills, if any. The map
+    /// conditions need a change since the last sample of the process, a
+    /// map which is there from the start has not appeared.
+    fn reached(&mut self, maps: &Maps) -> Option<String> {
+        let rss = maps.total(Metric::Rss);
+        let find = |name: &Option<String>| {
+            name.as_ref()
+                .and_then(|name| maps.maps.iter().find(|m| m.name.contains(&name[..])))
         };
-        let get_hex = |s: &String| -> Result<u64, String> {
-            u64::from_str_radix(s, 16).map_err(|e| -> String { e.to_string() })
+        let (appeared, still_there) = (find(&self.appears), find(&self.gone));
+        let now = (appeared.is_some(), still_there.is_some());
+        let before = self.present.insert(maps.pid, now);
+        if let Some(limit) = self.rss.filter(|&limit| rss > limit) {
+            return Some(format!(
+                "total rss of process {} is {} kB, above {} kB",
+                maps.pid,
+                format_total(rss, false),
+                format_total(limit, false)
+            ));
+        }
+        let (was_there, was_still_there) = before?;
+        if let (Some(m), false) = (appeared, was_there) {
+            return Some(format!(
+                "map {} appeared in process {} at {:x}-{:x}",
+                m.name, maps.pid, m.start, m.end
+            ));
+        }
+        match &self.gone {
+            Some(name) if was_still_there && !now.1 => Some(format!(
+                "no map of process {} contains {} any more",
+                maps.pid, name
+            )),
+            _ => None,
+        }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.present.remove(&pid);
+    }
+
+    /// Prints a final line if one of the snapshots fulfills a condition
+    /// and tells if the watching is over.
+    fn report(&mut self, snapshots: &[Maps], opts: &Options) -> bool {
+        let Some((pid, reason)) = snapshots
+            .iter()
+            .find_map(|maps| self.reached(maps).map(|reason| (maps.pid, reason)))
+        else {
+            return false;
         };
-        if further_lines.len() < 22 {
-            return Err("Expected at least 23 lines for entry.".to_string());
-        }
-        let mut name: String = "".to_string();
-        for i in 5..items.len() {
-            name.push_str(&items[i][..]);
-            name.push_str(" ");
-        }
-        Ok(Some(Map {
-            start: get_hex(&bounds[0])?,
-            end: get_hex(&bounds[1])?,
-            flags: items[1].clone(),
-            offset: get_hex(&items[2])?,
-            device_major: get_hex(&devices[0])? as u32,
-            device_minor: get_hex(&devices[1])? as u32,
-            inode: u64::from_str_radix(&items[4], 10).map_err(|e| e.to_string())?,
-            name,
-            size: get_number(&further_lines[0]),
-            kernel_page_size: get_number(&further_lines[1]),
-            mmu_page_size: get_number(&further_lines[2]),
-            rss: get_number(&further_lines[3]),
-            pss: get_number(&further_lines[4]),
-            shared_clean: get_number(&further_lines[5]),
-            shared_dirty: get_number(&further_lines[6]),
-            private_clean: get_number(&further_lines[7]),
-            private_dirty: get_number(&further_lines[8]),
-            referenced: get_number(&further_lines[9]),
-            anonymous: get_number(&further_lines[10]),
-            lazy_free: get_number(&further_lines[11]),
-            anon_huge_pages: get_number(&further_lines[12]),
-            shmem_pmd_mapped: get_number(&further_lines[13]),
-            file_pmd_mapped: get_number(&further_lines[14]),
-            shared_huge_tlb: get_number(&further_lines[15]),
-            private_huge_tlb: get_number(&further_lines[16]),
-            swap: get_number(&further_lines[17]),
-            swap_pss: get_number(&further_lines[18]),
-            locked: get_number(&further_lines[19]),
-            thp_eligible: get_number(&further_lines[20]) != 0,
-            protection_key: if further_lines.len() == 23 {
-                get_number(&further_lines[21])
-            } else {
-                0
-            },
-            vmflags: further_lines[further_lines.len() - 1].clone(),
-        }))
-    }
-
-    pub fn pretty_print(&self) {
-        println!("Range: {:x}-{:x}", self.start, self.end);
-        println!(
-            "Flags: {}, offset: {}, device: {:x}:{:x}, inode: {}",
-            self.flags, self.offset, self.device_major, self.device_minor, self.inode
-        );
-        println!("Name: {}", self.name);
-        println!(
-            "Kernel page size: {}, mmu page size: {}",
-            self.kernel_page_size, self.mmu_page_size
-        );
-        println!("Size: {}, Rss: {}, Pss: {}", self.size, self.rss, self.pss);
-        println!(
-            "Shared clean: {}, shared dirty: {}, private clean: {}, private dirty: {}",
-            self.shared_clean, self.shared_dirty, self.private_clean, self.private_dirty
-        );
-        println!(
-            "Referenced: {}, anonymous: {}, lazy free: {}",
-            self.referenced, self.anonymous, self.lazy_free
-        );
-        println!(
-            "Anon huge pages: {}, shmem pmd mapped: {}, file pmd mapped: {}",
-            self.anon_huge_pages, self.shmem_pmd_mapped, self.file_pmd_mapped
-        );
-        println!(
-            "Shared huge tlb: {}, private huge tlb: {}, swap: {}, swap pss: {}, locked: {}",
-            self.shared_huge_tlb, self.private_huge_tlb, self.swap, self.swap_pss, self.locked
-        );
+        match opts.format {
+            Format::Text => println!("Stopping: {}", reason),
+            Format::Json | Format::Ndjson => println!(
+                "{{\"pid\":{},\"time\":\"{}\",\"stopped\":{}}}",
+                pid,
+                opts.time_format.format(SystemTime::now()),
+                json_string(&reason)
+            ),
+            // Not to mix it into the rows:
+            Format::Csv => eprintln!("Stopping: {}", reason),
+        }
+        true
+    }
+}
+
+/// The number of maps of each watched process over the last samples.
+struct MapHistory {
+    window: usize,
+    counts: HashMap<i32, Vec<usize>>,
+}
+
+impl MapHistory {
+    fn new(window: usize) -> MapHistory {
+        MapHistory {
+            window,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Adds the number of maps of this sample and returns the history.
+    fn update(&mut self, maps: &Maps) -> &[usize] {
+        let counts = self.counts.entry(maps.pid).or_default();
+        counts.push(maps.maps.len());
+        if counts.len() > self.window {
+            counts.remove(0);
+        }
+        counts
+    }
+
+    /// Adds the sample and prints the history of its process.
+    fn print(&mut self, maps: &Maps, opts: &Options) {
+        let pid = maps.pid;
+        let counts = self.update(maps);
+        match opts.format {
+            Format::Text => println!(
+                "Maps of process {} in the last {} samples: {} ({})",
+                pid,
+                counts.len(),
+                sparkline(counts),
+                counts
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Format::Json | Format::Ndjson => println!(
+                "{{\"pid\":{},\"map_counts\":[{}]}}",
+                pid,
+                counts
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Format::Csv => {}
+        }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.counts.remove(&pid);
+    }
+}
+
+/// Shows the moving averages of the maps which are rising or falling,
+/// for `--avg-window` in place of the diff.
+fn print_averages(maps: &Maps, tracker: &mut AverageTracker, opts: &Options) {
+    if opts.format == Format::Text {
         println!(
-            "Thp eligible: {}, protection key: {}, vmflags: {}\n",
-            self.thp_eligible, self.protection_key, self.vmflags
+            "\nMoving averages of maps of pid {} at {}:",
+            maps.pid,
+            opts.time_format.format(maps.time)
         );
     }
+    for average in tracker.update(maps) {
+        if average.is_moving() && opts.shows(&average.map) {
+            average.print(opts);
+        }
+    }
+}
 
-    pub fn is_readonly_mmapped_file(&self) -> bool {
-        !self.name.is_empty() && self.inode != 0 && !self.vmflags.contains("wr")
+/// Describes what happens after a watched process has gone away.
+fn consequence(watched: usize, follow: bool, cgroup: bool) -> &'static str {
+    if watched > 1 {
+        "continuing with the others"
+    } else if follow {
+        "waiting for it to be restarted"
+    } else if cgroup {
+        "waiting for new processes in the cgroup"
+    } else {
+        "stopping"
     }
 }
 
-struct Maps {
-    pub pid: i32,
-    pub time: SystemTime,
-    pub maps: Vec<Map>,
+/// Reports that the first snapshot of `pid` could not be taken and exits,
+/// with status 3 if we lack the permission, 4 if it could not be parsed
+/// and 1 otherwise. Lacking the permission needs a different fix than a
+/// wrong PID.
+fn initial_read_failed(pid: i32, e: &MapError) -> ! {
+    match e {
+        MapError::ProcessGone(_) => eprintln!("Process {} does not exist.", pid),
+        MapError::PermissionDenied(_) => {
+            eprintln!(
+                "Permission denied for the maps of process {}, it probably belongs to another \
+                 user. Run mapwatcher as that user, with sudo or with CAP_SYS_PTRACE.",
+                pid
+            );
+            std::process::exit(EXIT_PERMISSION_DENIED);
+        }
+        _ => eprintln!("Could not read initial maps of process {}: {}", pid, e),
+    }
+    std::process::exit(input_error_status(e));
 }
 
-impl Maps {
-    fn get_maps(pid: i32) -> Result<Maps, String> {
-        let filename = format!("/proc/{}/smaps", pid);
-        let file =
-            fs::read_to_string(&filename).expect(&format!("Cannot read file {}", filename)[..]);
-        let mut lines = file.lines();
-        let mut res = Maps {
+/// Reports that a snapshot of `pid` could not be taken, after which the
+/// process is no longer watched. `consequence` tells what happens next.
+/// Returns the exit status should no process be left, 4 if the smaps
+/// could not be parsed and 2 otherwise.
+fn report_read_failure(pid: i32, e: &MapError, consequence: &str) -> i32 {
+    match e {
+        MapError::ProcessGone(_) => log!(Warn, "process {} has exited, {}", pid, consequence),
+        _ => log!(
+            Error,
+            "could not get maps of process {}: {}, {}",
             pid,
-            time: SystemTime::now(),
-            maps: vec![],
+            e,
+            consequence
+        ),
+    }
+    match e {
+        MapError::Parse { .. } => EXIT_PARSE_ERROR,
+        _ => EXIT_PROCESS_EXITED,
+    }
+}
+
+/// Counts the failed reads of every watched process in a row. Reading
+/// smaps can fail transiently, for example when the maps change during
+/// the read, so up to `max` samples are skipped before giving up.
+struct Retries {
+    max: u32,
+    failures: HashMap<i32, u32>,
+}
+
+impl Retries {
+    fn new(max: u32) -> Retries {
+        Retries {
+            max,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Tells if the failed read of `pid` is worth another try at the next
+    /// sample and warns about it. It is not if the process is gone, we
+    /// lack the permission or it failed too often in a row already.
+    fn transient(&mut self, pid: i32, e: &MapError) -> bool {
+        let failures = self.failures.entry(pid).or_default();
+        if *failures >= self.max
+            || matches!(e, MapError::ProcessGone(_) | MapError::PermissionDenied(_))
+        {
+            return false;
+        }
+        *failures += 1;
+        log!(
+            Warn,
+            "could not get maps of process {}: {}, keeping the previous sample (retry {} of {})",
+            pid,
+            e,
+            failures,
+            self.max
+        );
+        true
+    }
+
+    /// Starts counting anew after a successful read or for a new process.
+    fn reset(&mut self, pid: i32) {
+        self.failures.remove(&pid);
+    }
+}
+
+/// The start times of the watched processes, to notice when the PID of
+/// one which exited was reused by a different process. Where the kernel
+/// supports it, a pidfd is kept open for each of them as well, which
+/// tells for sure that the process exited, even if its PID is already in
+/// use again.
+struct StartTimes {
+    start_times: HashMap<i32, u64>,
+    pidfds: HashMap<i32, OwnedFd>,
+}
+
+impl StartTimes {
+    fn new() -> StartTimes {
+        StartTimes {
+            start_times: HashMap::new(),
+            pidfds: HashMap::new(),
+        }
+    }
+
+    /// Remembers the start time of a newly watched process.
+    fn remember(&mut self, pid: i32) {
+        if let Ok(start_time) = process_start_time(pid) {
+            self.start_times.insert(pid, start_time);
+        }
+        match open_pidfd(pid) {
+            Some(pidfd) => {
+                self.pidfds.insert(pid, pidfd);
+            }
+            None => log!(
+                Debug,
+                "no pidfd for process {}, going by its start time",
+                pid
+            ),
+        }
+    }
+
+    /// Tells if `pid` now belongs to another process than when it was
+    /// remembered.
+    fn reused(&self, pid: i32) -> bool {
+        match (self.start_times.get(&pid), process_start_time(pid)) {
+            (Some(&then), Ok(now)) => then != now,
+            _ => false,
+        }
+    }
+
+    /// Tells if the process exited according to its pidfd, which becomes
+    /// readable then. Without a pidfd we only find out when reading its
+    /// maps fails.
+    fn exited(&self, pid: i32) -> bool {
+        let Some(pidfd) = self.pidfds.get(&pid) else {
+            return false;
         };
-        loop {
-            let m = Map::parse_from_line_iterator(&mut lines);
-            match m {
-                Err(e) => {
-                    return Err(format!("Could not parse map: {}", e));
+        let mut fds = PollFd {
+            fd: pidfd.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        // Safe since `fds` is one valid entry, the timeout of 0 returns
+        // at once:
+        unsafe { poll(&mut fds, 1, 0) > 0 && fds.revents & POLLIN != 0 }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.start_times.remove(&pid);
+        self.pidfds.remove(&pid);
+    }
+}
+
+/// The command lines and start times of the watched processes, read
+/// once when a process is first seen.
+struct ProcessInfos(HashMap<i32, ProcessInfo>);
+
+impl ProcessInfos {
+    fn new() -> ProcessInfos {
+        ProcessInfos(HashMap::new())
+    }
+
+    /// Prints what `pid` is, reading it first if it is not known yet.
+    fn print(&mut self, pid: i32, opts: &Options) {
+        if let Entry::Vacant(e) = self.0.entry(pid) {
+            match ProcessInfo::read(pid) {
+                Ok(info) => {
+                    e.insert(info);
                 }
-                Ok(mm) => {
-                    if mm.is_none() {
-                        return Ok(res);
-                    }
-                    res.maps.push(mm.unwrap());
+                Err(_) => return,
+            }
+        }
+        self.0[&pid].print(opts);
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.0.remove(&pid);
+    }
+}
+
+/// The threads of the watched processes with their stack maps, for
+/// `--threads`. A stack map is found by the stack pointer of its thread.
+struct ThreadWatch(HashMap<i32, HashMap<i32, (Thread, Option<Map>)>>);
+
+impl ThreadWatch {
+    fn new() -> ThreadWatch {
+        ThreadWatch(HashMap::new())
+    }
+
+    /// Lists the threads of a newly watched process and afterwards
+    /// reports the threads which appeared or ended since the last sample.
+    fn update(&mut self, maps: &Maps, opts: &Options) {
+        let Ok(threads) = Thread::read_all(maps.pid) else {
+            return;
+        };
+        let first = !self.0.contains_key(&maps.pid);
+        let known = self.0.entry(maps.pid).or_default();
+        let mut next: HashMap<i32, (Thread, Option<Map>)> = HashMap::new();
+        for thread in threads {
+            // A stack found before is looked up again for its current
+            // values, the stack pointer is only known while blocked:
+            let stack = known
+                .get(&thread.tid)
+                .and_then(|(_, stack)| stack.as_ref())
+                .and_then(|m| maps.containing(m.start))
+                .or_else(|| thread.stack_pointer.and_then(|sp| maps.containing(sp)))
+                .cloned();
+            if first {
+                thread.print("THREAD", maps.pid, stack.as_ref(), opts);
+            } else if !known.contains_key(&thread.tid) {
+                thread.print("NEW", maps.pid, stack.as_ref(), opts);
+            }
+            next.insert(thread.tid, (thread, stack));
+        }
+        let mut ended: Vec<&(Thread, Option<Map>)> = known
+            .iter()
+            .filter(|(tid, _)| !next.contains_key(tid))
+            .map(|(_, entry)| entry)
+            .collect();
+        ended.sort_by_key(|(thread, _)| thread.tid);
+        for (thread, stack) in ended {
+            thread.print("GONE", maps.pid, stack.as_ref(), opts);
+        }
+        *known = next;
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.0.remove(&pid);
+    }
+}
+
+/// When something was last shown for each process. Samples without any
+/// changes are not shown, with `--heartbeat` a line tells now and then
+/// that a quiet process is still watched.
+struct Heartbeat {
+    every: Option<Duration>,
+    last: HashMap<i32, SystemTime>,
+}
+
+impl Heartbeat {
+    fn new(every: Option<Duration>) -> Heartbeat {
+        Heartbeat {
+            every,
+            last: HashMap::new(),
+        }
+    }
+
+    fn shown(&mut self, pid: i32, time: SystemTime) {
+        self.last.insert(pid, time);
+    }
+
+    /// Prints a heartbeat for a sample without changes if it is due.
+    fn quiet(&mut self, maps: &Maps, opts: &Options) {
+        let Some(every) = self.every else {
+            return;
+        };
+        let last = *self.last.entry(maps.pid).or_insert(maps.time);
+        if maps.time.duration_since(last).unwrap_or_default() >= every {
+            println!(
+                "pid {} at {}: no changes since {}",
+                maps.pid,
+                opts.time_format.format(maps.time),
+                opts.time_format.format(last)
+            );
+            self.last.insert(maps.pid, maps.time);
+        }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        self.last.remove(&pid);
+    }
+}
+
+/// The snapshots marked with `--mark`, by name and PID. Every line read
+/// from stdin names a mark, which takes a sample right away and keeps it
+/// under that name, like `before gc`. With `--diff-mark NAME` the diffs
+/// compare against the mark NAME once it is there.
+struct Marks {
+    requests: mpsc::Receiver<String>,
+    pending: Vec<String>, // the marks to take with this sample
+    marks: HashMap<String, HashMap<i32, Maps>>,
+    diff_to: Option<String>,
+}
+
+impl Marks {
+    fn new(diff_to: Option<String>) -> Marks {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                let name = line.trim().to_string();
+                if !name.is_empty() && sender.send(name).is_ok() {
+                    SAMPLE_REQUESTED.store(true, Ordering::SeqCst);
                 }
             }
+        });
+        Marks {
+            requests,
+            pending: vec![],
+            marks: HashMap::new(),
+            diff_to,
         }
     }
 
-    fn print_diff(&self, prev: &Maps) {
-        assert_eq!(self.pid, prev.pid);
-        let prev_time: OffsetDateTime = prev.time.into();
-        let new_time: OffsetDateTime = self.time.into();
-        println!(
-            "\nDifferences in maps of pid {} between {} and {}:",
-            self.pid,
-            prev_time
-                .format(&format_description::well_known::Rfc3339)
-                .unwrap(),
-            new_time
-                .format(&format_description::well_known::Rfc3339)
-                .unwrap(),
+    /// Takes the marks requested during the last wait for this sample.
+    fn take_requests(&mut self) {
+        self.pending = self.requests.try_iter().collect();
+    }
+
+    /// The mark to diff `pid` against, if it was already taken.
+    fn reference(&self, pid: i32) -> Option<&Maps> {
+        self.marks.get(self.diff_to.as_ref()?)?.get(&pid)
+    }
+
+    /// Keeps the snapshot `maps` under the pending marks, a mark which is
+    /// taken again is replaced.
+    fn update(&mut self, maps: &Maps, opts: &Options) {
+        for name in self.pending.iter() {
+            self.marks
+                .entry(name.clone())
+                .or_default()
+                .insert(maps.pid, maps.clone());
+            match opts.format {
+                Format::Text => println!("Marked the maps of process {} as {}.", maps.pid, name),
+                Format::Json | Format::Ndjson => println!(
+                    "{{\"pid\":{},\"time\":\"{}\",\"mark\":{}}}",
+                    maps.pid,
+                    opts.time_format.format(maps.time),
+                    json_string(name)
+                ),
+                Format::Csv => eprintln!("Marked the maps of process {} as {}.", maps.pid, name),
+            }
+        }
+    }
+
+    fn forget(&mut self, pid: i32) {
+        for marks in self.marks.values_mut() {
+            marks.remove(&pid);
+        }
+    }
+}
+
+/// The `--ignore-file` with the time it was last read, to read it again
+/// once it changed, so that the patterns can be tuned while watching.
+struct IgnoreFile {
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+impl IgnoreFile {
+    fn new(path: String) -> IgnoreFile {
+        IgnoreFile {
+            path,
+            modified: None,
+        }
+    }
+
+    /// Reads the patterns into `opts` if the file changed since the last
+    /// time. A file which cannot be read keeps the previous patterns.
+    fn reload(&mut self, opts: &mut Options) -> Result<(), String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .map_err(|e| format!("Cannot read ignore file {}: {}", self.path, e))?;
+        if self.modified == Some(modified) {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Cannot read ignore file {}: {}", self.path, e))?;
+        opts.ignored = parse_patterns(&contents);
+        self.modified = Some(modified);
+        log!(
+            Info,
+            "read {} patterns from ignore file {}",
+            opts.ignored.len(),
+            self.path
         );
-        // We assume that both maps are sorted by start address!
-        let mut i: usize = 0; // position in self.maps
-        let mut j: usize = 0; // position in prev.maps
-        let mut total_size: u64 = 0;
-        let mut total_mmapped: u64 = 0;
-        let mut total_mmapped_rss: u64 = 0;
-        let mut total_size_prev: u64 = 0;
-        let mut total_mmapped_prev: u64 = 0;
-        let mut total_mmapped_rss_prev: u64 = 0;
-        while i < self.maps.len() && j < prev.maps.len() {
-            let m = &self.maps[i];
-            total_size += m.size;
-            if m.is_readonly_mmapped_file() {
-                total_mmapped += m.size;
-                total_mmapped_rss += m.rss;
-            }
-            let p = &prev.maps[j];
-            total_size_prev += p.size;
-            if p.is_readonly_mmapped_file() {
-                total_mmapped_prev += p.size;
-                total_mmapped_rss_prev += p.rss;
-            }
-            if m.start < p.start {
-                if !m.name.is_empty() {
-                    println!(
-                        "MMAP: {:x}-{:x} size={} rss={} {}",
-                        m.start, m.end, m.size, m.rss, m.name
-                    );
+        Ok(())
+    }
+}
+
+/// The `--record` file the snapshots are appended to. A file whose name
+/// ends in `.gz`, or any with `--compress`, gets them through one `gzip`
+/// running for the whole watching, which compresses the stream as it
+/// goes and only ever holds a little of it.
+struct Recorder {
+    path: String,
+    compress: bool,
+    gzip: Option<Child>,
+}
+
+impl Recorder {
+    fn new(path: String, compress: bool) -> Recorder {
+        Recorder {
+            compress: compress || path.ends_with(".gz"),
+            path,
+            gzip: None,
+        }
+    }
+
+    fn write(&mut self, maps: &Maps) -> Result<(), MapError> {
+        if !self.compress {
+            return maps.record(&self.path);
+        }
+        if self.gzip.is_none() {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|e| MapError::io(&self.path, e))?;
+            // In its own process group, so that Ctrl-C does not cut off the
+            // end of the stream, gzip finishes once we close its input:
+            let gzip = Command::new("gzip")
+                .arg("-c")
+                .stdin(Stdio::piped())
+                .stdout(file)
+                .process_group(0)
+                .spawn()
+                .map_err(|e| MapError::io("gzip", e))?;
+            self.gzip = Some(gzip);
+        }
+        let stdin = self.gzip.as_mut().and_then(|gzip| gzip.stdin.as_mut());
+        match stdin {
+            Some(stdin) => stdin
+                .write_all(maps.to_record().as_bytes())
+                .map_err(|e| MapError::io(&self.path, e)),
+            None => Err(MapError::Other(format!(
+                "Cannot write {}: gzip is gone",
+                self.path
+            ))),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(mut gzip) = self.gzip.take() {
+            drop(gzip.stdin.take());
+            let _ = gzip.wait();
+        }
+    }
+}
+
+/// Resets the referenced and accessed bits of all pages of `pid` for
+/// `--clear-refs`, so that the referenced values of the next sample are
+/// the working set since now.
+fn clear_refs_of(pid: i32) -> Result<(), MapError> {
+    let path = format!("/proc/{}/clear_refs", pid);
+    fs::write(&path, "1").map_err(|e| MapError::of_process(pid, &path, e))
+}
+
+/// Reads a snapshot of `pid`, without the ignored maps if they are also
+/// left out of the totals.
+fn read_maps(pid: i32, opts: &Options) -> Result<Maps, MapError> {
+    let started = std::time::Instant::now();
+    let mut maps = Maps::get_maps_from(pid, opts.source)?;
+    log!(
+        Debug,
+        "read {} maps of process {} in {:.1} ms",
+        maps.maps.len(),
+        pid,
+        started.elapsed().as_secs_f64() * 1000.0
+    );
+    if opts.ignore_totals {
+        maps.maps.retain(|m| !opts.ignores(m));
+    }
+    Ok(maps)
+}
+
+/// Starts the command of `--exec`. Spawning returns once it was exec'd,
+/// so the first sample right after it sees the program start up. Its
+/// output goes to the original `stdout` rather than the `--output` file,
+/// and it gets no input if stdin is read for `--mark`.
+fn spawn_command(command: &[String], stdout: Option<OwnedFd>, mark: bool) -> Child {
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..]);
+    if let Some(stdout) = stdout {
+        cmd.stdout(Stdio::from(stdout));
+    }
+    if mark {
+        cmd.stdin(Stdio::null());
+    }
+    match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Cannot run {}: {}", command[0], e);
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Waits until the command of `--exec` ends, also if watching it stopped
+/// earlier, and returns its exit status to exit with. A command killed by
+/// a signal gives 128 plus the signal like in the shell.
+fn wait_for_command(child: &mut Child) -> i32 {
+    match child.wait() {
+        Ok(status) => status
+            .code()
+            .or_else(|| status.signal().map(|signal| 128 + signal))
+            .unwrap_or(EXIT_ERROR),
+        Err(e) => {
+            log!(Error, "cannot wait for the command: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Reports that the PID of a watched process now belongs to another one,
+/// after which it is no longer watched.
+fn report_reused(pid: i32, consequence: &str) {
+    log!(
+        Warn,
+        "PID {} was reused by a different process, {}",
+        pid,
+        consequence
+    );
+}
+
+/// The tags of the text output with what they mean, for the legend.
+const LEGEND: &[(&str, &str)] = &[
+    ("MMAP", "a new map"),
+    ("DROP", "a map which is gone"),
+    ("CHANGED", "a map whose values changed"),
+    (
+        "MOVED",
+        "a map which was dropped and mapped again at a new address",
+    ),
+    (
+        "LEAK?",
+        "a map whose rss grew in every sample of --leak-window",
+    ),
+    ("ALERT", "a total above an --alert-* threshold, on stderr"),
+    ("W^X VIOLATION", "a writable and executable map, on stderr"),
+];
+
+/// Prints what the tags and numbers of the diffs mean, once before the
+/// watching starts.
+fn print_legend(opts: &Options) {
+    println!("Legend:");
+    let width = LEGEND.iter().map(|(tag, _)| tag.len()).max().unwrap_or(0);
+    for (tag, description) in LEGEND {
+        // The padding goes after the tag, its color codes have no width:
+        let padding = " ".repeat(width - tag.len());
+        println!("  {}{}  {}", colored_tag(tag, opts), padding, description);
+    }
+    println!("  All sizes are in kB unless given with a unit, like with --human.");
+    println!("  \"rss=12 (was 8 diff 4, +2 kB/s)\" is the new value, the old one, the");
+    println!("  change and its rate per second. Use --no-legend to leave this out.\n");
+}
+
+/// Clears the terminal and moves the cursor home, for `--watch-top`.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Set by the SIGINT handler, the main loops stop when they see it.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the handler of the `--on-signal` signal, the main loops take
+/// the next sample when they see it.
+static SAMPLE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The interval of `--adaptive`, which is halved after a sample with
+/// changes, down to `min`, and doubled after a quiet one, up to `max`.
+struct Adaptive {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Adaptive {
+    /// Starts with `delay`, the bounds default to an eighth of it and
+    /// eight times it.
+    fn new(delay: Duration, min: Option<Duration>, max: Option<Duration>) -> Adaptive {
+        let min = min.unwrap_or(delay / 8);
+        let max = max.unwrap_or(delay * 8);
+        Adaptive {
+            min,
+            max,
+            current: delay.clamp(min, max),
+        }
+    }
+
+    fn pace(&self) -> Pace {
+        Pace::Every(self.current)
+    }
+
+    /// Adapts the interval after a sample, `changed` tells if any process
+    /// showed events, and reports the new interval if it changed.
+    fn update(&mut self, changed: bool, opts: &mut Options) {
+        let next = if changed {
+            (self.current / 2).max(self.min)
+        } else {
+            (self.current * 2).min(self.max)
+        };
+        if next == self.current {
+            return;
+        }
+        self.current = next;
+        if opts.requested_interval.is_some() {
+            opts.requested_interval = Some(next);
+        }
+        match opts.format {
+            Format::Text => println!("Sampling every {} now.", format_duration(next)),
+            Format::Json | Format::Ndjson => println!(
+                "{{\"time\":\"{}\",\"interval\":{:.3}}}",
+                opts.time_format.format(SystemTime::now()),
+                next.as_secs_f64()
+            ),
+            Format::Csv => eprintln!("Sampling every {} now.", format_duration(next)),
+        }
+    }
+}
+
+/// When to take the next sample: after a fixed delay or whenever the
+/// signal of `--on-signal` arrives.
+#[derive(Clone, Copy)]
+enum Pace {
+    Every(Duration),
+    OnSignal,
+}
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    fn dup2(oldfd: i32, newfd: i32) -> i32;
+    fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    fn syscall(number: i64, ...) -> i64;
+    fn poll(fds: *mut PollFd, nfds: u64, timeout: i32) -> i32;
+}
+
+/// The same on all architectures, since Linux 5.3:
+const SYS_PIDFD_OPEN: i64 = 434;
+const POLLIN: i16 = 1;
+const F_DUPFD_CLOEXEC: i32 = 1030;
+
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+/// Opens a pidfd for `pid`, or nothing on kernels without pidfds.
+fn open_pidfd(pid: i32) -> Option<OwnedFd> {
+    // Safe since pidfd_open only takes the PID and flags:
+    let fd = unsafe { syscall(SYS_PIDFD_OPEN, pid, 0u32) };
+    // Safe since a new file descriptor belongs to nobody else:
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+const SIGINT: i32 = 2;
+
+extern "C" fn on_sigint(_signum: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn install_sigint_handler() {
+    // Safe since the handler only touches an atomic:
+    unsafe {
+        signal(SIGINT, on_sigint);
+    }
+}
+
+extern "C" fn on_sample_signal(_signum: i32) {
+    SAMPLE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn install_sample_handler(signum: i32) {
+    // Safe since the handler only touches an atomic:
+    unsafe {
+        signal(signum, on_sample_signal);
+    }
+}
+
+/// Sends everything printed to stdout into the file at `path` for
+/// `--output`, creating missing parent directories. Alerts and errors
+/// stay on stderr.
+fn redirect_stdout(path: &str, append: bool) -> Result<OwnedFd, String> {
+    if let Some(dir) = Path::new(path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create directory {}: {}", dir.display(), e))?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| format!("Cannot open output file {}: {}", path, e))?;
+    // Safe since both are valid file descriptors, the file's copy is
+    // closed when it is dropped. The original stdout is kept for the
+    // command of --exec, closed on exec of anything else:
+    let original = unsafe { fcntl(1, F_DUPFD_CLOEXEC, 0) };
+    if original < 0 || unsafe { dup2(file.as_raw_fd(), 1) } < 0 {
+        return Err(format!(
+            "Cannot write to output file {}: {}",
+            path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    // Safe since nothing else owns the new descriptor:
+    Ok(unsafe { OwnedFd::from_raw_fd(original) })
+}
+
+/// Waits until the next sample is due, in small steps, so that an
+/// interrupt ends the wait early. Returns false if we were interrupted.
+fn wait_for_sample(pace: Pace) -> bool {
+    // Everything of the last sample goes out before we wait, also when
+    // stdout is a pipe. Stdout is line buffered, but the screen clearing
+    // of --watch-top is no full line:
+    let _ = std::io::stdout().flush();
+    let step = Duration::from_millis(100);
+    let mut left = match pace {
+        Pace::Every(delay) => delay,
+        Pace::OnSignal => Duration::MAX,
+    };
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        if left.is_zero() || SAMPLE_REQUESTED.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        let d = left.min(step);
+        thread::sleep(d);
+        left -= d;
+    }
+    false
+}
+
+fn main() {
+    let Args {
+        mut opts,
+        log_level,
+        file,
+        prometheus,
+        record,
+        compress,
+        replay_file,
+        diff_files,
+        baseline,
+        adaptive,
+        min_interval,
+        max_interval,
+        mark,
+        diff_mark,
+        convert,
+        name,
+        follow_name,
+        cgroup,
+        rollup,
+        all,
+        exec,
+        leak_window,
+        map_history,
+        avg_window,
+        alert_rss,
+        alert_pss,
+        alert_swap,
+        alert_growth,
+        alert_growth_metric,
+        alert_growth_since_start,
+        wx_alert,
+        exit_on_alert,
+        max_iterations,
+        until_rss,
+        until_map_appears,
+        until_map_gone,
+        max_retries,
+        once,
+        header,
+        legend,
+        threads,
+        clear_refs,
+        summary_interval,
+        heartbeat,
+        on_signal,
+        ignore_file,
+        show_interval,
+        output,
+        append,
+        positional: mut args,
+    } = Args::parse(env::args().skip(1));
+    set_log_level(log_level);
+    // The stdout we were started with, if --output replaced it:
+    let mut original_stdout = output.as_ref().map(|path| {
+        redirect_stdout(path, append).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_ERROR);
+        })
+    });
+    if opts.ignore_totals && ignore_file.is_none() {
+        eprintln!("--ignore-totals needs --ignore-file");
+        std::process::exit(EXIT_ERROR);
+    }
+    let mut ignore_file = ignore_file.map(IgnoreFile::new);
+    if let Some(ignore_file) = ignore_file.as_mut() {
+        if let Err(e) = ignore_file.reload(&mut opts) {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+    let format = opts.format;
+    let mut alerts = Alerts {
+        rss: alert_rss,
+        pss: alert_pss,
+        swap: alert_swap,
+        wx: wx_alert,
+        exit: exit_on_alert,
+        growth_percent: alert_growth,
+        growth_metric: alert_growth_metric,
+        growth_since_start: alert_growth_since_start,
+        references: HashMap::new(),
+    };
+    if format == Format::Csv && (rollup || opts.group_by.is_some()) {
+        eprintln!("--format csv cannot be used with --rollup or --group-by");
+        std::process::exit(EXIT_ERROR);
+    }
+    if (prometheus.is_some() || leak_window.is_some() || map_history.is_some() || record.is_some())
+        && rollup
+    {
+        eprintln!(
+            "--prometheus, --leak-window, --map-history and --record cannot be used with --rollup"
+        );
+        std::process::exit(EXIT_ERROR);
+    }
+    if avg_window.is_some() && (rollup || opts.group_by.is_some() || opts.watch_top.is_some()) {
+        eprintln!("--avg-window cannot be used with --rollup, --group-by or --watch-top");
+        std::process::exit(EXIT_ERROR);
+    }
+    if baseline.is_some() && (rollup || avg_window.is_some()) {
+        eprintln!("--baseline cannot be used with --rollup or --avg-window");
+        std::process::exit(EXIT_ERROR);
+    }
+    if (min_interval.is_some() || max_interval.is_some()) && !adaptive {
+        eprintln!("--min-interval and --max-interval need --adaptive");
+        std::process::exit(EXIT_ERROR);
+    }
+    if min_interval
+        .zip(max_interval)
+        .is_some_and(|(min, max)| min > max)
+    {
+        eprintln!("--min-interval cannot be larger than --max-interval");
+        std::process::exit(EXIT_ERROR);
+    }
+    if adaptive && (rollup || all || once || on_signal.is_some()) {
+        eprintln!("--adaptive cannot be used with --rollup, --all, --once or --on-signal");
+        std::process::exit(EXIT_ERROR);
+    }
+    if mark && (rollup || all || once || avg_window.is_some() || baseline.is_some()) {
+        eprintln!(
+            "--mark and --diff-mark cannot be used with --rollup, --all, --once, --avg-window \
+             or --baseline"
+        );
+        std::process::exit(EXIT_ERROR);
+    }
+    if wx_alert && rollup {
+        eprintln!("--wx-alert cannot be used with --rollup");
+        std::process::exit(EXIT_ERROR);
+    }
+    let mut until = Until {
+        rss: until_rss,
+        appears: until_map_appears,
+        gone: until_map_gone,
+        present: HashMap::new(),
+    };
+    if rollup && (until.rss.is_some() || until.appears.is_some() || until.gone.is_some()) {
+        eprintln!(
+            "--until-rss, --until-map-appears and --until-map-gone cannot be used with --rollup"
+        );
+        std::process::exit(EXIT_ERROR);
+    }
+    if clear_refs && rollup {
+        eprintln!("--clear-refs cannot be used with --rollup");
+        std::process::exit(EXIT_ERROR);
+    }
+    if all && (rollup || once || name.is_some() || cgroup.is_some() || format == Format::Csv) {
+        eprintln!("--all cannot be used with --rollup, --once, --name, --cgroup or --format csv");
+        std::process::exit(EXIT_ERROR);
+    }
+    if threads && rollup {
+        eprintln!("--threads cannot be used with --rollup");
+        std::process::exit(EXIT_ERROR);
+    }
+    if exec.is_some() && (rollup || all || once || name.is_some() || cgroup.is_some()) {
+        eprintln!("--exec cannot be used with --rollup, --all, --once, --name or --cgroup");
+        std::process::exit(EXIT_ERROR);
+    }
+    if cgroup.is_some() && (rollup || name.is_some()) {
+        eprintln!("--cgroup cannot be used with --rollup or --name");
+        std::process::exit(EXIT_ERROR);
+    }
+    // Updates the textfile for node_exporter and the recording after each
+    // sample, if wanted:
+    let mut recorder = record.map(|path| Recorder::new(path, compress));
+    let mut export = |snapshots: &[Maps], opts: &Options| {
+        if let Some(path) = &prometheus {
+            if let Err(e) = write_prometheus(path, snapshots, opts) {
+                log!(Error, "cannot write the Prometheus metrics: {}", e);
+            }
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            for maps in snapshots {
+                if let Err(e) = recorder.write(maps) {
+                    log!(Error, "cannot record the snapshot: {}", e);
                 }
-                i += 1;
-            } else if m.start > p.start {
-                if !p.name.is_empty() {
-                    println!(
-                        "DROP: {:x}-{:x} size={} rss={} {}",
-                        p.start, p.end, p.size, p.rss, p.name
-                    );
+            }
+        }
+    };
+    if let Some((target, path)) = convert {
+        convert_recording(&path, &target, &mut opts);
+        return;
+    }
+    match format {
+        Format::Text => println!("mapwatcher Version {}", VERSION),
+        Format::Json | Format::Ndjson => {}
+        Format::Csv => println!("{}", CSV_HEADER),
+    }
+    install_sigint_handler();
+    if let Some(path) = replay_file {
+        replay(&path, &opts);
+        return;
+    }
+    if let Some((old, new)) = diff_files {
+        let old_maps = read_dump(&old);
+        read_dump(&new).print_diff(&old_maps, &opts);
+        return;
+    }
+    if let Some(path) = file {
+        match Maps::from_file(&path, 0) {
+            Ok(maps) => {
+                maps.pretty_print(&opts);
+                export(std::slice::from_ref(&maps), &opts);
+            }
+            Err(e) => {
+                eprintln!("Could not read maps from {}: {}", path, e);
+                std::process::exit(input_error_status(&e));
+            }
+        }
+        return;
+    }
+    if follow_name && name.is_none() {
+        eprintln!("--follow-name needs --name");
+        std::process::exit(EXIT_ERROR);
+    }
+    // With --once and --on-signal there is no DELAY:
+    let delay_args = if once || on_signal.is_some() { 0 } else { 1 };
+    let mut pids = if let Some(path) = &cgroup {
+        if args.len() != delay_args {
+            usage();
+        }
+        match read_cgroup_pids(path) {
+            Ok(pids) => pids,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    } else if all || exec.is_some() {
+        if args.len() != delay_args {
+            usage();
+        }
+        // The command of --exec is only started right before watching:
+        vec![]
+    } else if let Some(name) = &name {
+        if args.len() != delay_args {
+            usage();
+        }
+        match find_process_by_name(name) {
+            Ok(pid) => vec![pid],
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    } else {
+        if args.len() != delay_args + 1 {
+            usage();
+        }
+        match parse_pids(&args.remove(0)) {
+            Ok(pids) => pids,
+            Err(e) => {
+                eprintln!("Need PID or comma separated PIDs as first argument: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    };
+    if once {
+        show_once(&pids, rollup, &opts);
+        return;
+    }
+    let pace = match on_signal {
+        Some(signum) => {
+            install_sample_handler(signum);
+            Pace::OnSignal
+        }
+        None => match parse_delay(&args[0]) {
+            Ok(delay) => {
+                if show_interval {
+                    opts.requested_interval = Some(delay);
                 }
-                j += 1;
-            } else {
-                if !m.name.is_empty() {
-                    // Same map, see if there was a diff:
-                    let enddiff = if m.end != p.end {
-                        format!(" (was {})", p.end,)
-                    } else {
-                        "".to_string()
+                Pace::Every(delay)
+            }
+            Err(e) => {
+                eprintln!("Need delay like 2, 500ms or 1m as last argument: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        },
+    };
+    if all {
+        watch_all(pace, max_iterations, opts.top.unwrap_or(10), &opts);
+        if format == Format::Text {
+            println!("Goodbye!");
+        }
+        return;
+    }
+    if rollup {
+        let status = watch_rollup(
+            &pids,
+            pace,
+            max_iterations,
+            &opts,
+            &mut alerts,
+            header,
+            Retries::new(max_retries),
+        );
+        if format == Format::Text {
+            println!("Goodbye!");
+        }
+        if let Some(status) = status {
+            std::process::exit(status);
+        }
+        return;
+    }
+    // Every diff compares against it instead of the previous sample:
+    let mut baseline = baseline.map(|path| read_dump(&path));
+    let mut adaptive = match pace {
+        Pace::Every(delay) if adaptive => Some(Adaptive::new(delay, min_interval, max_interval)),
+        _ => None,
+    };
+    let mut marks = mark.then(|| Marks::new(diff_mark));
+    let mut child = exec.map(|command| spawn_command(&command, original_stdout.take(), mark));
+    if let Some(child) = &child {
+        pids = vec![child.id() as i32];
+    }
+    let mut prev_maps: Vec<Maps> = vec![];
+    for &pid in pids.iter() {
+        match read_maps(pid, &opts) {
+            Ok(maps) => prev_maps.push(maps),
+            // Processes in a cgroup come and go, one might have exited
+            // since reading the members:
+            Err(_) if cgroup.is_some() && !process_exists(pid) => {}
+            Err(e) => {
+                // The command of --exec may be done before we got to it,
+                // its status is what counts then:
+                if let Some(child) = child.as_mut() {
+                    if let Ok(Some(_)) = child.try_wait() {
+                        std::process::exit(wait_for_command(child));
+                    }
+                }
+                initial_read_failed(pid, &e)
+            }
+        }
+    }
+    let clear_screen = format == Format::Text && opts.watch_top.is_some();
+    if clear_screen {
+        print!("{}", CLEAR_SCREEN);
+    }
+    let mut infos = ProcessInfos::new();
+    let mut retries = Retries::new(max_retries);
+    // The exit status once no process is left, 4 if one was dropped
+    // because its smaps could not be parsed:
+    let mut status = EXIT_PROCESS_EXITED;
+    let mut threads = threads.then(ThreadWatch::new);
+    for maps in prev_maps.iter() {
+        if !clear_screen {
+            infos.print(maps.pid, &opts);
+        }
+        // The snapshot is still taken for the first diff, just not shown:
+        if !opts.quiet {
+            if format == Format::Text && !clear_screen {
+                println!("Got initial maps of process {}:", maps.pid);
+            }
+            maps.pretty_print(&opts);
+            maps.print_totals(&opts);
+        }
+        if let Some(threads) = threads.as_mut() {
+            threads.update(maps, &opts);
+        }
+        alerts.check_maps(maps);
+    }
+    export(&prev_maps, &opts);
+    let mut leaks = leak_window.map(LeakTracker::new);
+    let mut history = map_history.map(MapHistory::new);
+    let mut averages = avg_window.map(AverageTracker::new);
+    let mut start_times = StartTimes::new();
+    for maps in prev_maps.iter() {
+        start_times.remember(maps.pid);
+        if let Some(tracker) = averages.as_mut() {
+            tracker.update(maps);
+        }
+        if let Some(tracker) = leaks.as_mut() {
+            tracker.update(maps);
+        }
+        if let Some(history) = history.as_mut() {
+            history.update(maps);
+        }
+    }
+    // For the summary at the end of the run:
+    let mut first_maps = prev_maps.clone();
+    let mut samples: HashMap<i32, u64> = prev_maps.iter().map(|m| (m.pid, 1)).collect();
+    let mut last_full: HashMap<i32, Maps> = HashMap::new();
+    let mut heartbeat = Heartbeat::new(heartbeat);
+    if format == Format::Text && !clear_screen {
+        if legend {
+            print_legend(&opts);
+        }
+        println!("Starting to observe...\n");
+    }
+    let follow_name = name.filter(|_| follow_name);
+    let mut remaining = max_iterations;
+    while !prev_maps.is_empty() || follow_name.is_some() || cgroup.is_some() {
+        if until.report(&prev_maps, &opts) || remaining == Some(0) {
+            break;
+        }
+        remaining = remaining.map(|n| n - 1);
+        if clear_refs {
+            for maps in prev_maps.iter() {
+                if let Err(e) = clear_refs_of(maps.pid) {
+                    log!(Error, "cannot clear the referenced bits: {}", e);
+                }
+            }
+        }
+        if !wait_for_sample(adaptive.as_ref().map_or(pace, Adaptive::pace)) {
+            break;
+        }
+        if let Some(ignore_file) = ignore_file.as_mut() {
+            if let Err(e) = ignore_file.reload(&mut opts) {
+                log!(Warn, "{}, keeping the previous patterns", e);
+            }
+        }
+        if let Some(marks) = marks.as_mut() {
+            marks.take_requests();
+        }
+        if clear_screen {
+            print!("{}", CLEAR_SCREEN);
+        }
+        let watched = prev_maps.len();
+        let before = if cgroup.is_some() {
+            prev_maps.clone()
+        } else {
+            vec![]
+        };
+        let mut next_maps: Vec<Maps> = vec![];
+        let mut gone: Vec<i32> = vec![];
+        let mut changed = false;
+        // Reaps the command of --exec once it exited, until then it is a
+        // zombie with an empty smaps:
+        if let Some(child) = child.as_mut() {
+            let _ = child.try_wait();
+        }
+        for prev in prev_maps.into_iter() {
+            let consequence = consequence(watched, follow_name.is_some(), cgroup.is_some());
+            if start_times.exited(prev.pid) {
+                log!(Warn, "process {} has exited, {}", prev.pid, consequence);
+                gone.push(prev.pid);
+                continue;
+            }
+            match read_maps(prev.pid, &opts) {
+                Ok(m) if start_times.reused(m.pid) => {
+                    report_reused(m.pid, consequence);
+                    gone.push(m.pid);
+                }
+                Ok(mut m) => {
+                    m.carry_ages(&prev);
+                    retries.reset(m.pid);
+                    *samples.entry(m.pid).or_default() += 1;
+                    // With --summary-interval only every Nth diff is a full one,
+                    // against the snapshot of the last full one:
+                    let brief =
+                        summary_interval.is_some_and(|n| !(samples[&m.pid] - 1).is_multiple_of(n));
+                    if let Some(baseline) = baseline.as_mut() {
+                        // The reference may come from any process:
+                        baseline.pid = m.pid;
+                    }
+                    let mark = marks.as_ref().and_then(|marks| marks.reference(m.pid));
+                    let since = match (&baseline, mark, summary_interval) {
+                        (Some(baseline), _, _) => baseline,
+                        (None, Some(mark), _) => mark,
+                        (None, None, Some(_)) => last_full
+                            .get(&m.pid)
+                            .or_else(|| first_maps.iter().find(|f| f.pid == m.pid))
+                            .unwrap_or(&prev),
+                        (None, None, None) => &prev,
                     };
-                    let sizediff = if m.size != p.size {
-                        format!(" (was {} diff {})", p.size, m.size as i64 - p.size as i64)
+                    let events = m.diff(since);
+                    let has_events = !opts.shown_events(&events).is_empty();
+                    changed |= has_events;
+                    let shown = if brief {
+                        if header {
+                            infos.print(m.pid, &opts);
+                        }
+                        m.print_total_changes(&prev, &opts);
+                        true
                     } else {
-                        "".to_string()
+                        match averages.as_mut() {
+                            Some(tracker) => {
+                                if header {
+                                    infos.print(m.pid, &opts);
+                                }
+                                print_averages(&m, tracker, &opts);
+                                true
+                            }
+                            None => m.print_events(since, &events, &opts, || {
+                                if header {
+                                    infos.print(m.pid, &opts);
+                                }
+                            }),
+                        }
                     };
-                    let rssdiff = if m.rss != p.rss {
-                        format!(" (was {} diff {})", p.rss, m.rss as i64 - p.rss as i64)
+                    if shown {
+                        heartbeat.shown(m.pid, m.time);
                     } else {
-                        "".to_string()
-                    };
-                    if !enddiff.is_empty() || !sizediff.is_empty() || !rssdiff.is_empty() {
-                        println!(
-                            "CHANGED: {:x}-{:x}{} size={}{} rss={}{} {}",
-                            m.start, m.end, enddiff, m.size, sizediff, m.rss, rssdiff, m.name
-                        );
+                        heartbeat.quiet(&m, &opts);
+                    }
+                    if shown && !brief {
+                        if format == Format::Text && !clear_screen {
+                            if let Some(first) = first_maps.iter().find(|f| f.pid == m.pid) {
+                                m.print_trend(first, &opts);
+                            }
+                        }
+                        if opts.totals {
+                            m.print_totals(&opts);
+                        }
+                        if let Some(history) = history.as_mut() {
+                            history.print(&m, &opts);
+                        }
+                        if summary_interval.is_some() {
+                            last_full.insert(m.pid, m.clone());
+                        }
                     }
+                    if let Some(threads) = threads.as_mut() {
+                        threads.update(&m, &opts);
+                    }
+                    if let Some(marks) = marks.as_mut() {
+                        marks.update(&m, &opts);
+                    }
+                    alerts.check_maps(&m);
+                    if let Some(tracker) = leaks.as_mut() {
+                        for leak in tracker.update(&m) {
+                            if opts.shows(&leak.map) {
+                                leak.print(&opts);
+                            }
+                        }
+                    }
+                    next_maps.push(m);
+                }
+                Err(e) if retries.transient(prev.pid, &e) => next_maps.push(prev),
+                Err(e) => {
+                    status = status.max(report_read_failure(prev.pid, &e, consequence));
+                    gone.push(prev.pid);
                 }
-                i += 1;
-                j += 1;
             }
         }
-        if i < self.maps.len() {
-            while i < self.maps.len() {
-                let m = &self.maps[i];
-                total_size += m.size;
-                if m.is_readonly_mmapped_file() {
-                    total_mmapped += m.size;
-                    total_mmapped_rss += m.rss;
-                }
-                if !m.name.is_empty() {
+        for pid in gone {
+            if let Some(tracker) = leaks.as_mut() {
+                tracker.forget(pid);
+            }
+            if let Some(history) = history.as_mut() {
+                history.forget(pid);
+            }
+            if let Some(tracker) = averages.as_mut() {
+                tracker.forget(pid);
+            }
+            start_times.forget(pid);
+            infos.forget(pid);
+            retries.reset(pid);
+            alerts.forget(pid);
+            until.forget(pid);
+            last_full.remove(&pid);
+            if let Some(marks) = marks.as_mut() {
+                marks.forget(pid);
+            }
+            heartbeat.forget(pid);
+            if let Some(threads) = threads.as_mut() {
+                threads.forget(pid);
+            }
+            // A process found later under the same PID starts afresh:
+            first_maps.retain(|first| first.pid != pid);
+        }
+        prev_maps = next_maps;
+        if let Some(adaptive) = adaptive.as_mut() {
+            adaptive.update(changed, &mut opts);
+        }
+        if let (true, Some(name)) = (prev_maps.is_empty(), &follow_name) {
+            // Not found is fine, the process might not be restarted yet:
+            if let Ok(maps) = find_process_by_name(name).and_then(|pid| read_maps(pid, &opts)) {
+                if format == Format::Text {
                     println!(
-                        "MMAP: {:x}-{:x} size={} rss={} {}",
-                        m.start, m.end, m.size, m.rss, m.name
+                        "Process {} was restarted with pid {}, got its maps:",
+                        name, maps.pid
                     );
                 }
-                i += 1;
+                infos.print(maps.pid, &opts);
+                if !opts.quiet {
+                    maps.pretty_print(&opts);
+                }
+                alerts.check_maps(&maps);
+                start_times.remember(maps.pid);
+                samples.insert(maps.pid, 1);
+                first_maps.push(maps.clone());
+                prev_maps.push(maps);
             }
         }
-        if j < self.maps.len() {
-            while j < prev.maps.len() {
-                let m = &prev.maps[j];
-                total_size_prev += m.size;
-                if m.is_readonly_mmapped_file() {
-                    total_mmapped_prev += m.size;
-                    total_mmapped_rss_prev += m.rss;
+        if let Some(path) = &cgroup {
+            let members = match read_cgroup_pids(path) {
+                Ok(pids) => pids,
+                Err(e) => {
+                    eprintln!("{}, stopping", e);
+                    std::process::exit(EXIT_ERROR);
                 }
-                if !m.name.is_empty() {
-                    println!(
-                        "DROP: {:x}-{:x} size={} rss={} {}",
-                        m.start, m.end, m.size, m.rss, m.name
-                    );
+            };
+            prev_maps.retain(|maps| {
+                let member = members.contains(&maps.pid);
+                if !member && format == Format::Text {
+                    println!("Process {} left the cgroup", maps.pid);
+                }
+                member
+            });
+            for &pid in members.iter() {
+                if prev_maps.iter().any(|maps| maps.pid == pid) {
+                    continue;
+                }
+                // Gone again already or not readable, try next time:
+                let Ok(maps) = read_maps(pid, &opts) else {
+                    continue;
+                };
+                if format == Format::Text {
+                    println!("Process {} joined the cgroup, got its maps:", pid);
+                }
+                infos.print(maps.pid, &opts);
+                if !opts.quiet {
+                    maps.pretty_print(&opts);
                 }
-                j += 1;
+                alerts.check_maps(&maps);
+                if let Some(tracker) = leaks.as_mut() {
+                    tracker.update(&maps);
+                }
+                if let Some(history) = history.as_mut() {
+                    history.update(&maps);
+                }
+                if let Some(tracker) = averages.as_mut() {
+                    tracker.update(&maps);
+                }
+                start_times.remember(maps.pid);
+                samples.insert(maps.pid, 1);
+                first_maps.push(maps.clone());
+                prev_maps.push(maps);
             }
+            print_cgroup_totals(path, &prev_maps, &before, &opts);
         }
-        let size_diff = if total_size != total_size_prev {
-            format!(
-                " (was {} diff {})",
-                total_size_prev.to_formatted_string(&Locale::en),
-                total_size as i64 - total_size_prev as i64
-            )
-        } else {
-            "".to_string()
-        };
-        let mmapped_diff = if total_mmapped != total_mmapped_prev {
-            format!(
-                " (was {} diff {})",
-                total_mmapped_prev.to_formatted_string(&Locale::en),
-                total_mmapped as i64 - total_mmapped_prev as i64
-            )
-        } else {
-            "".to_string()
-        };
-        let mmapped_rss_diff = if total_mmapped_rss != total_mmapped_rss_prev {
-            format!(
-                " (was {} diff {})",
-                total_mmapped_rss_prev.to_formatted_string(&Locale::en),
-                total_mmapped_rss as i64 - total_mmapped_rss_prev as i64
-            )
-        } else {
-            "".to_string()
-        };
-        println!(
-            "Total size: {}{}, mmapped files: {}{}, mmapped rss: {}{}",
-            total_size.to_formatted_string(&Locale::en),
-            size_diff,
-            total_mmapped.to_formatted_string(&Locale::en),
-            mmapped_diff,
-            total_mmapped_rss.to_formatted_string(&Locale::en),
-            mmapped_rss_diff,
-        );
+        export(&prev_maps, &opts);
     }
-}
-
-const VERSION: &str = "0.3";
-
-fn main() {
-    println!("mapwatcher Version {}", VERSION);
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: mapwatcher PID DELAY");
-        std::process::exit(0);
-    }
-    let pid = args[1].parse::<i32>().expect("Need PID as first argument");
-    let delay = args[2]
-        .parse::<f64>()
-        .expect("Need delay in seconds as second argument");
-    let mut prev_maps = Maps::get_maps(pid).expect("Could not read initial maps.");
-    println!("Got initial maps of process:");
-    for m in prev_maps.maps.iter() {
-        m.pretty_print();
-    }
-    println!("Starting to observe...\n");
-    loop {
-        thread::sleep(Duration::from_secs_f64(delay));
-        let m = Maps::get_maps(pid);
-        if let Err(e) = m {
-            eprintln!("Could not get maps: {}", e);
-            break;
+    // Ctrl-C, --max-iterations or an --until condition, processes which
+    // exited have no summary:
+    for maps in prev_maps.iter() {
+        if let Some(first) = first_maps.iter().find(|f| f.pid == maps.pid) {
+            maps.print_summary(first, samples[&maps.pid], &opts);
         }
-        let m = m.unwrap();
-        m.print_diff(&prev_maps);
-        prev_maps = m;
     }
-    println!("Goodbye!");
+    if format == Format::Text {
+        println!("Goodbye!");
+    }
+    if let Some(child) = child.as_mut() {
+        std::process::exit(wait_for_command(child));
+    }
+    // Without anything to wait for the loop only ends early that way:
+    if prev_maps.is_empty() && follow_name.is_none() && cgroup.is_none() {
+        std::process::exit(status);
+    }
 }